@@ -0,0 +1,223 @@
+use std::io::BufRead;
+use std::process::{Child, Command, Stdio};
+
+use async_channel::Receiver;
+
+use crate::errors::Res;
+use crate::output::{Connection, Status, DISCONNECTED_MARKER};
+
+/// The format accounts print their counts in, for them to be read back.
+pub const FORMAT: &str = "{new} {total}";
+
+/// What an account last printed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reading {
+    Counts {
+        new_count: usize,
+        count: u32,
+        connected: bool,
+    },
+    /// A problem retrying won't fix, shown as is.
+    Message(String),
+}
+
+impl Reading {
+    /// Read a line of `FORMAT`, maybe after an icon and label, and the
+    /// disconnected marker.
+    pub fn parse(line: &str) -> Reading {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let counts = match words.as_slice() {
+            [.., new, total] => new.parse().ok().zip(total.parse().ok()),
+            _ => None,
+        };
+        let Some((new_count, count)) = counts else {
+            return Reading::Message(line.into());
+        };
+        Reading::Counts {
+            new_count,
+            count,
+            connected: words.len() < 3 || words[words.len() - 3] != DISCONNECTED_MARKER,
+        }
+    }
+}
+
+/// The counts of the accounts heard from summed, disconnected if any is,
+/// with a tooltip line each. The first message of an account is shown
+/// instead, named after it.
+pub fn combine(readings: &[(String, Option<Reading>)]) -> Result<(Status, Connection), String> {
+    let mut status = Status::new(0, 0);
+    let mut conn = Connection::Connected;
+    let mut lines = Vec::new();
+    for (name, reading) in readings {
+        match reading {
+            Some(Reading::Counts {
+                new_count,
+                count,
+                connected,
+            }) => {
+                status.new_count += new_count;
+                status.count += count;
+                if !connected {
+                    conn = Connection::Disconnected;
+                }
+                lines.push(format!("{}: {} new of {}", name, new_count, count));
+            }
+            Some(Reading::Message(msg)) => return Err(format!("{}: {}", name, msg)),
+            None => lines.push(format!("{}: connecting", name)),
+        }
+    }
+    status.tooltip = Some(lines.join("\n"));
+    Ok((status, conn))
+}
+
+/// The account whose style the block takes: the first with new mail.
+pub fn shown(readings: &[(String, Option<Reading>)]) -> Option<usize> {
+    readings.iter().position(
+        |(_, reading)| matches!(reading, Some(Reading::Counts { new_count, .. }) if *new_count > 0),
+    )
+}
+
+/// The accounts' processes, and their lines as read, with `None` once one
+/// exits.
+pub struct Children {
+    pub children: Vec<Child>,
+    pub lines: Receiver<(usize, Option<String>)>,
+}
+
+/// Run this program again for each of `count` accounts, with the same
+/// arguments and `--account <index>`. Their logs share stderr.
+pub fn spawn(count: usize) -> Res<Children> {
+    let exe = std::env::current_exe()?;
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    let (tx, lines) = async_channel::unbounded();
+    let mut children = Vec::new();
+    for i in 0..count {
+        let mut child = Command::new(&exe)
+            .args(&args)
+            .arg("--account")
+            .arg(i.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            // Only this process talks to systemd.
+            .env_remove("NOTIFY_SOCKET")
+            .spawn()?;
+        let stdout = child.stdout.take().expect("piped stdout");
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            for line in std::io::BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if tx.send_blocking((i, Some(line))).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send_blocking((i, None));
+        });
+        children.push(child);
+    }
+    Ok(Children { children, lines })
+}
+
+impl Children {
+    /// Pass a signal on to the accounts still running.
+    pub fn signal(&self, signal: libc::c_int) {
+        for child in &self.children {
+            // Safety: only sends a signal, to a child not yet waited for.
+            unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Reading;
+    use crate::output::{Connection, OutputMode, Status, Style};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Reading::Counts {
+                new_count: 2,
+                count: 42,
+                connected: true
+            },
+            Reading::parse("2 42")
+        );
+        assert_eq!(
+            Reading::Counts {
+                new_count: 2,
+                count: 42,
+                connected: false
+            },
+            Reading::parse("✉ work ? 2 42")
+        );
+        assert_eq!(
+            Reading::Message("no mailbox INBOX".into()),
+            Reading::parse("no mailbox INBOX")
+        );
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        let mut status = Status::new(2, 42);
+        status.quota = Some(85);
+        let render = |style: &Style| {
+            let line = OutputMode::Plain.render(
+                super::FORMAT,
+                false,
+                style,
+                &status,
+                Connection::Connected,
+            );
+            Reading::parse(&line)
+        };
+        // As accounts run, with the quota left to the parent.
+        assert_eq!(
+            Reading::Counts {
+                new_count: 2,
+                count: 42,
+                connected: true
+            },
+            render(&Style::default())
+        );
+        let shown = Style {
+            show_quota: true,
+            ..Default::default()
+        };
+        assert_eq!(Reading::Message("2 42 85% full".into()), render(&shown));
+    }
+
+    #[test]
+    fn test_combine() {
+        let mut readings = vec![
+            ("home".to_string(), Some(Reading::parse("2 42"))),
+            ("work".to_string(), None),
+        ];
+        let (status, conn) = super::combine(&readings).unwrap();
+        assert_eq!((2, 42), (status.new_count, status.count));
+        assert_eq!(Some(0), super::shown(&readings));
+        assert_eq!(Connection::Connected, conn);
+        assert_eq!(
+            Some("home: 2 new of 42\nwork: connecting"),
+            status.tooltip.as_deref()
+        );
+
+        readings[0].1 = Some(Reading::parse("0 40"));
+        assert_eq!(None, super::shown(&readings));
+        readings[1].1 = Some(Reading::parse("? 1 10"));
+        assert_eq!(Some(1), super::shown(&readings));
+        readings[0].1 = Some(Reading::parse("2 42"));
+        let (status, conn) = super::combine(&readings).unwrap();
+        assert_eq!((3, 52), (status.new_count, status.count));
+        assert_eq!(Connection::Disconnected, conn);
+
+        readings[1].1 = Some(Reading::parse("not a Gmail server"));
+        assert_eq!(Some(0), super::shown(&readings));
+        assert_eq!(
+            Err("work: not a Gmail server".into()),
+            super::combine(&readings).map(|_| ())
+        );
+    }
+}
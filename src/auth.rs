@@ -0,0 +1,238 @@
+use async_imap::{Client, Session};
+use async_std::io::{Read, Write};
+
+use crate::caps::Caps;
+use crate::creds::Creds;
+use crate::errors::Res;
+
+/// How to authenticate once connected.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// Pick from the advertised AUTH= capabilities
+    Auto,
+    /// The plain IMAP LOGIN command
+    Login,
+    /// SASL PLAIN
+    Plain,
+    /// SASL XOAUTH2, using the password as the bearer token; OAUTHBEARER is
+    /// preferred when advertised
+    Xoauth2,
+    /// SASL OAUTHBEARER (RFC 7628), using the password as the bearer token
+    Oauthbearer,
+    /// SASL EXTERNAL, the TLS client certificate standing for the user
+    External,
+}
+
+impl AuthMethod {
+    /// Settle on a concrete method given the pre-login capabilities.
+    ///
+    /// LOGIN is kept whenever allowed, matching the historical behaviour.
+    pub fn resolve(self, caps: &Caps) -> Res<AuthMethod> {
+        let login_disabled = caps.has("LOGINDISABLED");
+        match self {
+            AuthMethod::Auto if !login_disabled => Ok(AuthMethod::Login),
+            AuthMethod::Auto if caps.has("AUTH=PLAIN") => Ok(AuthMethod::Plain),
+            AuthMethod::Auto | AuthMethod::Login if login_disabled => Err(
+                "server advertises LOGINDISABLED, refusing plaintext login; enable TLS/STARTTLS"
+                    .into(),
+            ),
+            AuthMethod::Xoauth2 if caps.has("AUTH=OAUTHBEARER") => Ok(AuthMethod::Oauthbearer),
+            AuthMethod::External if !caps.has("AUTH=EXTERNAL") => Err(
+                "server doesn't advertise AUTH=EXTERNAL for the client certificate, give a password"
+                    .into(),
+            ),
+            m => Ok(m),
+        }
+    }
+
+    /// Whether the password is an OAuth2 token, which may need refreshing.
+    pub fn is_oauth(self) -> bool {
+        matches!(self, AuthMethod::Xoauth2 | AuthMethod::Oauthbearer)
+    }
+}
+
+/// SASL PLAIN initial response, without an authorization identity.
+struct Plain<'a> {
+    user: &'a str,
+    pass: &'a str,
+}
+
+impl async_imap::Authenticator for Plain<'_> {
+    type Response = String;
+
+    fn process(&mut self, _: &[u8]) -> String {
+        format!("\0{}\0{}", self.user, self.pass)
+    }
+}
+
+/// XOAUTH2 initial response. On failure the server sends a JSON error as a
+/// challenge, which must be answered with an empty response.
+struct XOAuth2<'a> {
+    user: &'a str,
+    token: &'a str,
+    sent: bool,
+}
+
+impl async_imap::Authenticator for XOAuth2<'_> {
+    type Response = String;
+
+    fn process(&mut self, _: &[u8]) -> String {
+        if std::mem::replace(&mut self.sent, true) {
+            return String::new();
+        }
+        format!("user={}\x01auth=Bearer {}\x01\x01", self.user, self.token)
+    }
+}
+
+/// OAUTHBEARER initial response. As with XOAUTH2, an error challenge gets a
+/// dummy response, here a lone ^A.
+struct OAuthBearer<'a> {
+    user: &'a str,
+    token: &'a str,
+    host: &'a str,
+    port: u16,
+    sent: bool,
+}
+
+impl async_imap::Authenticator for OAuthBearer<'_> {
+    type Response = String;
+
+    fn process(&mut self, _: &[u8]) -> String {
+        if std::mem::replace(&mut self.sent, true) {
+            return "\x01".into();
+        }
+        format!(
+            "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+            self.user.replace('=', "=3D").replace(',', "=2C"),
+            self.host,
+            self.port,
+            self.token
+        )
+    }
+}
+
+/// SASL EXTERNAL response, empty for the identity of the certificate.
+struct External;
+
+impl async_imap::Authenticator for External {
+    type Response = String;
+
+    fn process(&mut self, _: &[u8]) -> String {
+        String::new()
+    }
+}
+
+/// Authenticate with a method previously settled by `AuthMethod::resolve`.
+pub async fn login<T>(
+    c: Client<T>,
+    method: AuthMethod,
+    cred: &Creds,
+) -> Result<Session<T>, (async_imap::error::Error, Client<T>)>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let (user, pass) = (cred.user.as_str(), cred.pass.as_str());
+    log::debug!("authenticating with {:?}", method);
+    match method {
+        AuthMethod::Auto | AuthMethod::Login => c.login(user, pass).await,
+        AuthMethod::Plain => c.authenticate("PLAIN", Plain { user, pass }).await,
+        AuthMethod::Xoauth2 => {
+            let auth = XOAuth2 {
+                user,
+                token: pass,
+                sent: false,
+            };
+            c.authenticate("XOAUTH2", auth).await
+        }
+        AuthMethod::Oauthbearer => {
+            let auth = OAuthBearer {
+                user,
+                token: pass,
+                host: &cred.host,
+                port: cred.port,
+                sent: false,
+            };
+            c.authenticate("OAUTHBEARER", auth).await
+        }
+        AuthMethod::External => c.authenticate("EXTERNAL", External).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::AuthMethod;
+    use crate::caps::Caps;
+    use async_imap::Authenticator;
+
+    #[test]
+    fn test_resolve() {
+        let none = Caps::default();
+        assert_eq!(AuthMethod::Login, AuthMethod::Auto.resolve(&none).unwrap());
+        assert_eq!(AuthMethod::Plain, AuthMethod::Plain.resolve(&none).unwrap());
+
+        let disabled = Caps::from_names(&["LOGINDISABLED"]);
+        AuthMethod::Auto.resolve(&disabled).unwrap_err();
+        AuthMethod::Login.resolve(&disabled).unwrap_err();
+
+        let plain = Caps::from_names(&["LOGINDISABLED", "AUTH=PLAIN"]);
+        assert_eq!(AuthMethod::Plain, AuthMethod::Auto.resolve(&plain).unwrap());
+
+        let xoauth2 = Caps::from_names(&["AUTH=XOAUTH2"]);
+        assert_eq!(
+            AuthMethod::Xoauth2,
+            AuthMethod::Xoauth2.resolve(&xoauth2).unwrap()
+        );
+        AuthMethod::External.resolve(&none).unwrap_err();
+        let external = Caps::from_names(&["AUTH=EXTERNAL"]);
+        assert_eq!(
+            AuthMethod::External,
+            AuthMethod::External.resolve(&external).unwrap()
+        );
+        let bearer = Caps::from_names(&["AUTH=XOAUTH2", "AUTH=OAUTHBEARER"]);
+        assert_eq!(
+            AuthMethod::Oauthbearer,
+            AuthMethod::Xoauth2.resolve(&bearer).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_plain() {
+        let mut a = super::Plain {
+            user: "me",
+            pass: "secret",
+        };
+        assert_eq!("\0me\0secret", a.process(b""));
+    }
+
+    #[test]
+    fn test_xoauth2() {
+        let mut a = super::XOAuth2 {
+            user: "me@example.com",
+            token: "tok",
+            sent: false,
+        };
+        assert_eq!(
+            "user=me@example.com\x01auth=Bearer tok\x01\x01",
+            a.process(b"")
+        );
+        assert_eq!("", a.process(b"{\"status\":\"401\"}"));
+    }
+
+    #[test]
+    fn test_oauthbearer() {
+        let mut a = super::OAuthBearer {
+            user: "me,a=b",
+            token: "tok",
+            host: "imap.example.com",
+            port: 993,
+            sent: false,
+        };
+        assert_eq!(
+            "n,a=me=2Ca=3Db,\x01host=imap.example.com\x01port=993\x01auth=Bearer tok\x01\x01",
+            a.process(b"")
+        );
+        assert_eq!("\x01", a.process(b"{\"status\":\"invalid_token\"}"));
+    }
+}
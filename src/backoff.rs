@@ -1,18 +1,40 @@
-/// A never-ending slice iterator, for throttling retry operations.
+use std::time::{Duration, Instant};
+
+/// How long a connection must stay up, a poll through, for the backoff to
+/// be reset: a server dropping sessions right after login is not hammered.
+pub const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// A never-ending slice iterator, for throttling retry operations: once at
+/// the last value, it keeps yielding it. An empty slice yields 0.
 pub struct Backoff<'a> {
     i: usize,
     v: &'a [u64],
+    /// Fraction each value is moved by either way, as `jitter_fraction`.
+    jitter: f64,
+    rng: fastrand::Rng,
 }
 
 impl<'a> Backoff<'a> {
     pub fn new(v: &'a [u64]) -> Backoff<'a> {
-        Backoff { i: 0, v }
+        Backoff {
+            i: 0,
+            v,
+            jitter: 0.0,
+            rng: fastrand::Rng::new(),
+        }
     }
 
-    pub fn next(&mut self) -> u64 {
-        let ret = self.v[self.i];
-        self.i = std::cmp::min(self.i + 1, self.v.len() - 1);
-        ret
+    /// Yield each value moved by up to `fraction` of it either way.
+    pub fn jittered(self, fraction: f64) -> Backoff<'a> {
+        Backoff {
+            jitter: fraction,
+            ..self
+        }
+    }
+
+    /// The value the next call to `next` yields, before any jitter.
+    pub fn peek(&self) -> u64 {
+        self.v.get(self.i).copied().unwrap_or(0)
     }
 
     pub fn reset(&mut self) {
@@ -20,32 +42,144 @@ impl<'a> Backoff<'a> {
     }
 }
 
+impl Iterator for Backoff<'_> {
+    type Item = u64;
+
+    /// Never `None`.
+    fn next(&mut self) -> Option<u64> {
+        let ret = jitter_fraction(self.peek(), self.jitter, &mut self.rng);
+        self.i = std::cmp::min(self.i + 1, self.v.len().saturating_sub(1));
+        Some(ret)
+    }
+}
+
+/// Whether a connection made at `connected_at`, if a poll went through on
+/// it, has been up long enough as of `now` to reset the backoff.
+pub fn stable(connected_at: Option<Instant>, now: Instant) -> bool {
+    connected_at.is_some_and(|t| now.saturating_duration_since(t) >= STABLE_AFTER)
+}
+
+/// `base` moved by up to `spread` either way, so that clients started together
+/// drift apart. The generator is given for tests to seed it.
+pub fn jitter(base: u64, spread: u64, rng: &mut fastrand::Rng) -> u64 {
+    let low = base.saturating_sub(spread);
+    rng.u64(low..=base + spread)
+}
+
+/// `base` moved by up to its `fraction` either way, as `jitter`.
+pub fn jitter_fraction(base: u64, fraction: f64, rng: &mut fastrand::Rng) -> u64 {
+    jitter(base, (base as f64 * fraction).round() as u64, rng)
+}
+
+/// Check a `--backoff-jitter` is a fraction, from 0 to 1.
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        _ => Err(format!("{} is not a fraction from 0 to 1", s)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_stable() {
+        let connected = Instant::now();
+        let after = |secs| connected + Duration::from_secs(secs);
+        // Never polled.
+        assert!(!super::stable(None, after(3600)));
+        assert!(!super::stable(Some(connected), after(2)));
+        assert!(super::stable(Some(connected), after(30)));
+        assert!(super::stable(Some(connected), after(3600)));
+    }
+
     #[test]
-    #[should_panic]
     fn test_empty() {
-        super::Backoff::new(&[]).next();
+        let mut b = super::Backoff::new(&[]);
+        assert_eq!(Some(0), b.next());
+        assert_eq!(Some(0), b.next());
+        assert_eq!(0, b.peek());
     }
 
     #[test]
     fn test_single() {
         let mut b = super::Backoff::new(&[2]);
-        assert_eq!(2, b.next());
-        assert_eq!(2, b.next());
-        assert_eq!(2, b.next());
+        assert_eq!(Some(2), b.next());
+        assert_eq!(Some(2), b.next());
+        assert_eq!(Some(2), b.next());
     }
 
     #[test]
     fn test_advance() {
         let mut b = super::Backoff::new(&[1, 2, 3]);
-        assert_eq!(1, b.next());
-        assert_eq!(2, b.next());
-        assert_eq!(3, b.next());
-        assert_eq!(3, b.next());
+        assert_eq!(Some(1), b.next());
+        assert_eq!(Some(2), b.next());
+        assert_eq!(Some(3), b.next());
+        assert_eq!(Some(3), b.next());
         b.reset();
-        assert_eq!(1, b.next());
-        assert_eq!(2, b.next());
+        assert_eq!(Some(1), b.next());
+        assert_eq!(Some(2), b.next());
+    }
+
+    #[test]
+    fn test_iterator() {
+        let schedule = vec![0, 30, 90];
+        let b = super::Backoff::new(&schedule);
+        assert_eq!(vec![0, 30, 90, 90, 90], b.take(5).collect::<Vec<_>>());
+        let total: u64 = super::Backoff::new(&schedule).take(4).sum();
+        assert_eq!(210, total);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut b = super::Backoff::new(&[1, 2]);
+        for _ in 0..3 {
+            let peeked = b.peek();
+            assert_eq!(peeked, b.peek());
+            assert_eq!(Some(peeked), b.next());
+        }
+    }
+
+    #[test]
+    fn test_jittered() {
+        let mut b = super::Backoff::new(&[100, 600]).jittered(0.2);
+        assert!((80..=120).contains(&b.next().unwrap()));
+        for _ in 0..100 {
+            assert!((480..=720).contains(&b.next().unwrap()));
+        }
+        assert_eq!(600, b.peek());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut rng = fastrand::Rng::with_seed(7);
+        let values: Vec<u64> = (0..100)
+            .map(|_| super::jitter(1700, 30, &mut rng))
+            .collect();
+        assert!(values.iter().all(|v| (1670..=1730).contains(v)));
+        assert!(values.iter().any(|v| *v != values[0]));
+        assert_eq!(1700, super::jitter(1700, 0, &mut rng));
+        assert!(super::jitter(10, 30, &mut rng) <= 40);
+    }
+
+    #[test]
+    fn test_jitter_fraction() {
+        let mut rng = fastrand::Rng::with_seed(7);
+        for _ in 0..100 {
+            assert!((480..=720).contains(&super::jitter_fraction(600, 0.2, &mut rng)));
+        }
+        assert_eq!(600, super::jitter_fraction(600, 0.0, &mut rng));
+        assert_eq!(0, super::jitter_fraction(0, 0.2, &mut rng));
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        assert_eq!(Ok(0.2), super::parse_fraction("0.2"));
+        assert_eq!(Ok(0.0), super::parse_fraction("0"));
+        assert!(super::parse_fraction("1.5").is_err());
+        assert!(super::parse_fraction("-0.1").is_err());
+        assert!(super::parse_fraction("some").is_err());
     }
 }
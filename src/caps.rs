@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+
+use async_imap::imap_proto::{Capability, Response, ResponseCode, Status};
+use async_imap::types::UnsolicitedResponse;
+use async_imap::Connection;
+use async_std::io::{Read, Write};
+
+use crate::errors::Res;
+
+/// Capability names advertised by the server, upper-cased for lookup.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Caps(HashSet<String>);
+
+/// Capabilities of a server before and after login, as cached in the state
+/// file to spare the round-trips on reconnect.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ServerCaps {
+    pub pre: Caps,
+    pub post: Caps,
+}
+
+impl Caps {
+    pub fn has(&self, name: &str) -> bool {
+        self.0.contains(&name.to_ascii_uppercase())
+    }
+
+    pub fn from_names(names: &[&str]) -> Caps {
+        Caps(names.iter().map(|n| n.to_ascii_uppercase()).collect())
+    }
+
+    fn insert(&mut self, cap: &Capability<'_>) {
+        let name = match cap {
+            Capability::Imap4rev1 => "IMAP4REV1".to_string(),
+            Capability::Auth(m) => format!("AUTH={}", m.to_ascii_uppercase()),
+            Capability::Atom(a) => a.to_ascii_uppercase(),
+        };
+        self.0.insert(name);
+    }
+
+    /// Query capabilities over a connection which isn't logged in yet.
+    ///
+    /// A capability code in the server greeting, if still unread, is merged in.
+    /// A BYE greeting is an error with the server's reason.
+    pub async fn query<T>(c: &mut Connection<T>) -> Res<Caps>
+    where
+        T: Read + Write + Unpin + std::fmt::Debug + Send,
+    {
+        let (tx, rx) = async_channel::unbounded();
+        let res = c.run_command_and_check_ok("CAPABILITY", Some(tx)).await;
+        let mut caps = Caps::default();
+        while let Ok(msg) = rx.try_recv() {
+            let UnsolicitedResponse::Other(res) = msg else {
+                continue;
+            };
+            match res.parsed() {
+                Response::Data {
+                    status: Status::Bye,
+                    information,
+                    ..
+                } => {
+                    let reason = information.as_deref().unwrap_or_default();
+                    return Err(format!("server said BYE: {}", reason).into());
+                }
+                Response::Capabilities(cs)
+                | Response::Data {
+                    code: Some(ResponseCode::Capabilities(cs)),
+                    ..
+                } => cs.iter().for_each(|cap| caps.insert(cap)),
+                _ => (),
+            }
+        }
+        res?;
+        Ok(caps)
+    }
+}
+
+impl From<&async_imap::types::Capabilities> for Caps {
+    fn from(cs: &async_imap::types::Capabilities) -> Caps {
+        use async_imap::types::Capability as Cap;
+        let mut caps = Caps::default();
+        for cap in cs.iter() {
+            caps.insert(&match cap {
+                Cap::Imap4rev1 => Capability::Imap4rev1,
+                Cap::Auth(m) => Capability::Auth(m.into()),
+                Cap::Atom(a) => Capability::Atom(a.into()),
+            });
+        }
+        caps
+    }
+}
+
+impl std::fmt::Display for Caps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<&str> = self.0.iter().map(String::as_str).collect();
+        names.sort_unstable();
+        f.write_str(&names.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_std::io::prelude::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_query() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(async move {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                sock.write_all(b"* OK [CAPABILITY IMAP4rev1 STARTTLS] hi\r\n")
+                    .await
+                    .unwrap();
+                // Wait for the whole command, the client may write it in pieces.
+                let mut line = String::new();
+                async_std::io::BufReader::new(sock.clone())
+                    .read_line(&mut line)
+                    .await
+                    .unwrap();
+                sock.write_all(b"* CAPABILITY IMAP4rev1 LOGINDISABLED AUTH=plain\r\n")
+                    .await
+                    .unwrap();
+                sock.write_all(b"A0001 OK done\r\n").await.unwrap();
+            });
+
+            let mut c = async_imap::Client::new(TcpStream::connect(addr).await.unwrap());
+            let caps = super::Caps::query(&mut c).await.unwrap();
+            assert!(caps.has("LOGINDISABLED"));
+            assert!(caps.has("starttls"));
+            assert!(caps.has("AUTH=PLAIN"));
+            assert!(!caps.has("IDLE"));
+        });
+    }
+}
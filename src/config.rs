@@ -0,0 +1,448 @@
+use async_std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::creds::Creds;
+use crate::errors::Res;
+use crate::output::Style;
+
+/// Settings read from the `--config` TOML file, or JSON on stdin.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub name: Option<String>,
+    pub label: Option<String>,
+    pub icon: Option<String>,
+    #[serde(rename = "color-new")]
+    pub color_new: Option<String>,
+    /// Where to get credentials from, first success wins.
+    #[serde(default)]
+    pub creds: Vec<Source>,
+    #[serde(default)]
+    pub folder: Vec<Folder>,
+    /// Block colors by search criteria, the first one matching anywhere wins.
+    #[serde(default, rename = "color-rule")]
+    pub color_rule: Vec<ColorRule>,
+    /// Styles of the accounts of several credentials files.
+    #[serde(default)]
+    pub account: Vec<Account>,
+}
+
+/// An `[[account]]` table: how the block looks while the account of the
+/// credentials file named `name`, less its extension, has the new mail.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    pub name: String,
+    pub label: Option<String>,
+    pub icon: Option<String>,
+    #[serde(rename = "color-new")]
+    pub color_new: Option<String>,
+}
+
+/// A credential source, as listed in `creds`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Source {
+    /// A muttrc file.
+    Mutt(PathBuf),
+    /// The host, user and pass of this file.
+    Inline,
+    /// The stdin line format.
+    Stdin,
+    /// The host and user of this file, with the password from a command such
+    /// as a keyring lookup.
+    PassCmd(String),
+}
+
+/// A watched folder, from a `[[folder]]` table.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Folder {
+    pub name: String,
+    /// SEARCH criteria counted as new in this folder.
+    pub criteria: Option<String>,
+}
+
+/// A `[[color-rule]]` table: the block color when any watched message
+/// matches `criteria`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColorRule {
+    pub criteria: String,
+    pub color: String,
+}
+
+/// An extra counter from `--watch name:folder:criteria`, rendered as the
+/// `{name}` token.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Watch {
+    pub name: String,
+    pub folder: String,
+    pub criteria: String,
+}
+
+impl std::str::FromStr for Watch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Watch, String> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(name), Some(folder), Some(criteria)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("expected name:folder:criteria, got {:?}", s));
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid token name {:?}", name));
+        }
+        if crate::output::TOKENS.contains(&name) {
+            return Err(format!("{{{}}} is already a token", name));
+        }
+        if folder.is_empty() || criteria.trim().is_empty() {
+            return Err(format!("missing folder or criteria in {:?}", s));
+        }
+        Ok(Watch {
+            name: name.into(),
+            folder: folder.into(),
+            criteria: criteria.trim().into(),
+        })
+    }
+}
+
+/// Substitute `${VAR}` with the environment variable, and `$$` with `$`.
+fn expand_env(s: &str) -> Res<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(tail) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix('{') {
+            let end = tail.find('}').ok_or("unterminated ${ in config")?;
+            let name = &tail[..end];
+            let value = std::env::var(name)
+                .map_err(|e| format!("cannot expand ${{{}}} in config: {}", name, e))?;
+            out.push_str(&value);
+            rest = &tail[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn expand_value(v: &mut toml::Value) -> Res<()> {
+    match v {
+        toml::Value::String(s) => *s = expand_env(s)?,
+        toml::Value::Array(a) => a.iter_mut().try_for_each(expand_value)?,
+        toml::Value::Table(t) => t.iter_mut().try_for_each(|(_, v)| expand_value(v))?,
+        _ => (),
+    }
+    Ok(())
+}
+
+impl Config {
+    pub async fn from_file(path: &Path) -> Res<Config> {
+        Config::parse(&async_std::fs::read_to_string(path).await?)
+    }
+
+    /// Parse TOML settings, expanding `${VAR}` in string values.
+    pub fn parse(c: &str) -> Res<Config> {
+        let mut v: toml::Value = toml::from_str(c)?;
+        expand_value(&mut v)?;
+        Ok(Config::deserialize(v)?)
+    }
+
+    pub fn from_stdin_json() -> Res<Config> {
+        log::debug!("waiting from json config on stdin");
+        Config::parse_json(&std::io::read_to_string(std::io::stdin())?)
+    }
+
+    pub fn parse_json(c: &str) -> Res<Config> {
+        Ok(serde_json::from_str(c)?)
+    }
+
+    /// Credentials given inline, rather than in a separate file.
+    pub fn creds(&self) -> Res<Creds> {
+        Ok(Creds {
+            host: self.host.clone().ok_or("no host in config")?,
+            port: self.port.unwrap_or(993),
+            user: self.user.clone().unwrap_or_default(),
+            pass: self.pass.clone().unwrap_or_default().into(),
+            pass_cmd: None,
+            expires: None,
+            auth: None,
+        })
+    }
+
+    /// Try each of the `creds` sources in turn.
+    pub async fn load_creds(&self) -> Res<Creds> {
+        let mut last = None;
+        for source in &self.creds {
+            let res = match source {
+                Source::Mutt(path) => Creds::from_mutt(path.as_path().into()).await,
+                Source::Inline => self.creds(),
+                Source::Stdin => Creds::from_stdin(),
+                Source::PassCmd(cmd) => self.creds().and_then(|mut c| {
+                    c.run_pass_cmd(cmd)?;
+                    Ok(c)
+                }),
+            };
+            match res {
+                Ok(c) => {
+                    log::debug!("using credentials from {:?}", source);
+                    return Ok(c);
+                }
+                Err(e) => {
+                    log::warn!("Cannot read credentials from {:?}: {}", source, e);
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| "no credential source".into()))
+    }
+
+    /// How the account's block is shown.
+    pub fn style(&self) -> Style {
+        Style {
+            name: self.name.clone(),
+            label: self.label.clone(),
+            icon: self.icon.clone(),
+            color_new: self.color_new.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// How the block of account `name` is shown: `base` with the settings
+    /// of its `[[account]]` table, if any, and named after it.
+    pub fn account_style(&self, base: &Style, name: &str) -> Style {
+        let mut style = Style {
+            name: Some(name.into()),
+            ..base.clone()
+        };
+        if let Some(account) = self.account.iter().find(|a| a.name == name) {
+            style.label = account.label.clone().or(style.label);
+            style.icon = account.icon.clone().or(style.icon);
+            style.color_new = account.color_new.clone().or(style.color_new);
+        }
+        style
+    }
+
+    /// Folders to watch, falling back to INBOX when none is configured.
+    pub fn folders(&self) -> Vec<Folder> {
+        if self.folder.is_empty() {
+            return vec![Folder {
+                name: "INBOX".into(),
+                criteria: None,
+            }];
+        }
+        self.folder.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_color_rules() {
+        let c = super::Config::parse(
+            "
+            [[color-rule]]
+            criteria = 'FLAGGED UNSEEN'
+            color = '#ff0000'
+
+            [[color-rule]]
+            criteria = 'UNSEEN'
+            color = '#ffff00'
+            ",
+        )
+        .unwrap();
+        assert_eq!(2, c.color_rule.len());
+        assert_eq!("FLAGGED UNSEEN", c.color_rule[0].criteria);
+        assert_eq!("#ffff00", c.color_rule[1].color);
+    }
+
+    #[test]
+    fn test_watch() {
+        let w: super::Watch = "drafts:Drafts:ALL".parse().unwrap();
+        assert_eq!("drafts", w.name);
+        assert_eq!("Drafts", w.folder);
+        assert_eq!("ALL", w.criteria);
+        let w: super::Watch = "mine:INBOX:FROM me:x".parse().unwrap();
+        assert_eq!("FROM me:x", w.criteria);
+
+        assert!("drafts:Drafts".parse::<super::Watch>().is_err());
+        assert!("new:INBOX:ALL".parse::<super::Watch>().is_err());
+        assert!("a b:INBOX:ALL".parse::<super::Watch>().is_err());
+        assert!("drafts:Drafts: ".parse::<super::Watch>().is_err());
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let c = super::Config::parse("").unwrap();
+        let folders = c.folders();
+        assert_eq!(1, folders.len());
+        assert_eq!("INBOX", folders[0].name);
+        assert_eq!(None, folders[0].criteria);
+    }
+
+    #[test]
+    fn test_parse_folders() {
+        let c = super::Config::parse(&textwrap::dedent(
+            "
+            [[folder]]
+            name = 'INBOX'
+
+            [[folder]]
+            name = 'Sent/confirmations'
+            criteria = 'UNSEEN SINCE 1-Jan-2024'
+            ",
+        ))
+        .unwrap();
+        let folders = c.folders();
+        assert_eq!(2, folders.len());
+        assert_eq!("INBOX", folders[0].name);
+        assert_eq!(None, folders[0].criteria);
+        assert_eq!("Sent/confirmations", folders[1].name);
+        assert_eq!(
+            Some("UNSEEN SINCE 1-Jan-2024"),
+            folders[1].criteria.as_deref()
+        );
+    }
+
+    #[test]
+    fn test_parse_style() {
+        let c = super::Config::parse(&textwrap::dedent(
+            "
+            name = 'work'
+            icon = '✉'
+            color-new = '#ff8800'
+            ",
+        ))
+        .unwrap();
+        let style = c.style();
+        assert_eq!(Some("work"), style.name.as_deref());
+        assert_eq!(None, style.label);
+        assert_eq!(Some("✉"), style.icon.as_deref());
+        assert_eq!(Some("#ff8800"), style.color_new.as_deref());
+    }
+
+    #[test]
+    fn test_account_style() {
+        use crate::output::{Connection, OutputMode, Status};
+        let c = super::Config::parse(&textwrap::dedent(
+            "
+            icon = '✉'
+
+            [[account]]
+            name = 'home'
+            label = 'Home'
+
+            [[account]]
+            name = 'work'
+            label = 'Work'
+            color-new = '#ff8800'
+            ",
+        ))
+        .unwrap();
+        let base = c.style();
+        let render = |name: &str| {
+            OutputMode::I3.render(
+                "{new}",
+                false,
+                &c.account_style(&base, name),
+                &Status::new(2, 42),
+                Connection::Connected,
+            )
+        };
+        assert_eq!(
+            r##"{"full_text":"✉ Home 2","short_text":"2","instance":"home","color":"#00cc00"}"##,
+            render("home")
+        );
+        assert_eq!(
+            r##"{"full_text":"✉ Work 2","short_text":"2","instance":"work","color":"#ff8800"}"##,
+            render("work")
+        );
+        assert_eq!(
+            r##"{"full_text":"✉ 2","short_text":"2","instance":"other","color":"#00cc00"}"##,
+            render("other")
+        );
+    }
+
+    #[test]
+    fn test_load_creds() {
+        let c = super::Config::parse(&textwrap::dedent(
+            "
+            user = 'me'
+            creds = ['inline', { pass-cmd = 'false' }, { mutt = '/nonexistent' }]
+            ",
+        ))
+        .unwrap();
+        async_std::task::block_on(c.load_creds()).unwrap_err();
+
+        let c = super::Config::parse(&textwrap::dedent(
+            "
+            host = 'imap.example.com'
+            user = 'me'
+            creds = [{ pass-cmd = 'false' }, { pass-cmd = 'echo secret' }, 'inline']
+            ",
+        ))
+        .unwrap();
+        let creds = async_std::task::block_on(c.load_creds()).unwrap();
+        assert_eq!("imap.example.com", creds.host);
+        assert_eq!("me", creds.user);
+        assert_eq!("secret", creds.pass);
+    }
+
+    #[test]
+    fn test_expand_env() {
+        std::env::set_var("IMAP_BLOCK_TEST_PASS", "s3cret");
+        assert_eq!(
+            "s3cret",
+            super::expand_env("${IMAP_BLOCK_TEST_PASS}").unwrap()
+        );
+        assert_eq!(
+            "a $ b $5 s3cret!",
+            super::expand_env("a $$ b $5 ${IMAP_BLOCK_TEST_PASS}!").unwrap()
+        );
+        super::expand_env("${IMAP_BLOCK_TEST_UNSET}").unwrap_err();
+        super::expand_env("${IMAP_BLOCK_TEST_PASS").unwrap_err();
+
+        let c = super::Config::parse("host = 'h'\npass = '${IMAP_BLOCK_TEST_PASS}'\n").unwrap();
+        assert_eq!("s3cret", c.creds().unwrap().pass);
+    }
+
+    #[test]
+    fn test_parse_unknown() {
+        assert!(super::Config::parse("[[folder]]\nname = 'INBOX'\ncriterion = 'ALL'\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let c = super::Config::parse_json(
+            r#"{"host": "imap.example.com", "user": "me", "pass": "secret",
+                "folder": [{"name": "Lists", "criteria": "UNSEEN FLAGGED"}]}"#,
+        )
+        .unwrap();
+        let creds = c.creds().unwrap();
+        assert_eq!("imap.example.com", creds.host);
+        assert_eq!(993, creds.port);
+        assert_eq!("me", creds.user);
+        assert_eq!("secret", creds.pass);
+        assert_eq!("Lists", c.folders()[0].name);
+    }
+
+    #[test]
+    fn test_creds_missing_host() {
+        super::Config::parse_json("{}")
+            .unwrap()
+            .creds()
+            .unwrap_err();
+    }
+}
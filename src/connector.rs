@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::backoff::Backoff;
+use crate::caps::ServerCaps;
+use crate::session::StartError;
+
+/// Why a connection attempt failed.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The server could not be reached, or TLS not set up: worth trying
+    /// again after a while.
+    Transport(Box<dyn Error>),
+    /// Reached, but not logged in.
+    Start(StartError),
+}
+
+/// Opens a connection up to a logged in session, for `establish` to retry.
+///
+/// Tests can stand in for the server with their own, failing as they see
+/// fit.
+pub trait Connector {
+    /// The session, along with whatever else the caller needs of it.
+    type Output;
+
+    /// One attempt, with the capabilities known from a previous session, if
+    /// trusted.
+    fn connect(
+        &mut self,
+        cached: Option<&ServerCaps>,
+    ) -> impl Future<Output = Result<Self::Output, ConnectError>>;
+}
+
+/// Fail an attempt taking over `limit`, as a transport failure, for a
+/// server whose firewall drops packets not to hang forever.
+pub async fn within<T>(
+    limit: Duration,
+    attempt: impl Future<Output = Result<T, ConnectError>>,
+) -> Result<T, ConnectError> {
+    match async_std::future::timeout(limit, attempt).await {
+        Ok(res) => res,
+        Err(_) => Err(ConnectError::Transport(
+            format!("timed out after {}s", limit.as_secs()).into(),
+        )),
+    }
+}
+
+/// Connect, waiting the next `backoff` step with `wait` after each transport
+/// failure. `backoff` is reset once connected.
+///
+/// `wait` resolves to false when waiting was cut short, such as by a signal,
+/// and `None` is returned. Login failures are left to the caller.
+pub async fn establish<C, W, F>(
+    connector: &mut C,
+    cached: Option<&ServerCaps>,
+    backoff: &mut Backoff<'_>,
+    mut wait: W,
+) -> Result<Option<C::Output>, StartError>
+where
+    C: Connector,
+    W: FnMut(u64) -> F,
+    F: Future<Output = bool>,
+{
+    loop {
+        match connector.connect(cached).await {
+            Ok(out) => {
+                backoff.reset();
+                return Ok(Some(out));
+            }
+            Err(ConnectError::Start(e)) => return Err(e),
+            Err(ConnectError::Transport(e)) => {
+                let secs = backoff.next().unwrap_or_default();
+                log::warn!("Failure connecting, retrying in {}s: {}", secs, e);
+                if !wait(secs).await {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+
+    use async_std::task::block_on;
+
+    use super::{ConnectError, Connector};
+    use crate::backoff::Backoff;
+    use crate::caps::ServerCaps;
+    use crate::session::StartError;
+
+    /// Fails with the given errors in turn, then connects.
+    struct Flaky {
+        failures: Vec<ConnectError>,
+        attempts: usize,
+    }
+
+    impl Connector for Flaky {
+        type Output = usize;
+
+        async fn connect(&mut self, _: Option<&ServerCaps>) -> Result<usize, ConnectError> {
+            self.attempts += 1;
+            match self.failures.is_empty() {
+                true => Ok(self.attempts),
+                false => Err(self.failures.remove(0)),
+            }
+        }
+    }
+
+    fn transport() -> ConnectError {
+        ConnectError::Transport("connection refused".into())
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut flaky = Flaky {
+            failures: vec![transport(), transport()],
+            attempts: 0,
+        };
+        let mut backoff = Backoff::new(&[1, 2, 3]);
+        backoff.next();
+        let waits = RefCell::new(Vec::new());
+        let wait = |secs| {
+            waits.borrow_mut().push(secs);
+            async { true }
+        };
+        let got = block_on(super::establish(&mut flaky, None, &mut backoff, wait));
+        assert_eq!(Some(3), got.unwrap());
+        // Advanced from where it was, then reset.
+        assert_eq!(vec![2, 3], waits.into_inner());
+        assert_eq!(1, backoff.peek());
+    }
+
+    #[test]
+    fn test_interrupted() {
+        let mut flaky = Flaky {
+            failures: vec![transport(), transport()],
+            attempts: 0,
+        };
+        let mut backoff = Backoff::new(&[1, 2, 3]);
+        let got = block_on(super::establish(
+            &mut flaky,
+            None,
+            &mut backoff,
+            |_| async { false },
+        ));
+        assert!(got.unwrap().is_none());
+        assert_eq!(1, flaky.attempts);
+        assert_eq!(2, backoff.peek());
+    }
+
+    #[test]
+    fn test_within() {
+        let limit = std::time::Duration::from_millis(10);
+        let hung = super::within(limit, std::future::pending::<Result<(), ConnectError>>());
+        match block_on(hung) {
+            Err(ConnectError::Transport(e)) => assert_eq!("timed out after 0s", e.to_string()),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        let quick = super::within(limit, async { Ok::<_, ConnectError>(1) });
+        assert_eq!(1, block_on(quick).unwrap());
+    }
+
+    #[test]
+    fn test_login_failure() {
+        let mut flaky = Flaky {
+            failures: vec![ConnectError::Start(StartError::Limit("busy".into()))],
+            attempts: 0,
+        };
+        let mut backoff = Backoff::new(&[1, 2, 3]);
+        let got = block_on(super::establish(
+            &mut flaky,
+            None,
+            &mut backoff,
+            |_| async { true },
+        ));
+        assert!(matches!(got, Err(StartError::Limit(_))));
+        // Not a transport failure, the backoff is left to the caller.
+        assert_eq!(1, backoff.peek());
+    }
+}
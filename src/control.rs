@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+use async_channel::{Receiver, Sender};
+use async_std::io::BufReader;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::path::Path;
+use async_std::prelude::*;
+use async_std::task::spawn;
+use futures::future::{select, Either};
+
+use crate::errors::Res;
+
+/// The longest snooze, a week, as longer ones are rather typos.
+const MAX_SNOOZE_MINUTES: u64 = 7 * 24 * 60;
+
+/// A command read from the control socket, one per line.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Disconnect and stop polling for a while.
+    Snooze(Duration),
+    /// End a snooze early.
+    Resume,
+    /// The UIDs of new messages as of the last poll, as JSON by folder.
+    Uids,
+    /// Move the read messages of INBOX to the archive folder.
+    ArchiveRead,
+}
+
+impl std::str::FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("snooze"), Some(m), None) => match m.parse::<u64>() {
+                Ok(m) if m <= MAX_SNOOZE_MINUTES => {
+                    Ok(Command::Snooze(Duration::from_secs(m * 60)))
+                }
+                Ok(m) => Err(format!("snooze of {} minutes is longer than a week", m)),
+                Err(e) => Err(format!("bad snooze minutes {:?}: {}", m, e)),
+            },
+            (Some("resume"), None, None) => Ok(Command::Resume),
+            (Some("uids"), None, None) => Ok(Command::Uids),
+            (Some("archive-read"), None, None) => Ok(Command::ArchiveRead),
+            _ => Err(format!("unknown command {:?}", line.trim())),
+        }
+    }
+}
+
+/// A command awaiting its one line answer.
+pub struct Request {
+    pub cmd: Command,
+    reply: Sender<String>,
+}
+
+impl Request {
+    pub fn reply(&self, msg: &str) {
+        let _ = self.reply.try_send(msg.into());
+    }
+}
+
+/// Accept commands on a unix socket at `path`, replacing any stale one.
+pub async fn listen(path: &Path) -> Res<Receiver<Request>> {
+    if path.exists().await {
+        async_std::fs::remove_file(path).await?;
+    }
+    let listener = UnixListener::bind(path).await?;
+    let (tx, rx) = async_channel::unbounded();
+    spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    spawn(serve(stream, tx.clone()));
+                }
+                Err(e) => log::warn!("Failure accepting control connection: {}", e),
+            }
+        }
+    });
+    Ok(rx)
+}
+
+async fn serve(stream: UnixStream, tx: Sender<Request>) {
+    let mut lines = BufReader::new(stream.clone()).lines();
+    let mut w = stream;
+    while let Some(Ok(line)) = lines.next().await {
+        let answer = match line.parse() {
+            Ok(cmd) => {
+                let (reply, answer) = async_channel::bounded(1);
+                if tx.send(Request { cmd, reply }).await.is_err() {
+                    return;
+                }
+                answer.recv().await.unwrap_or_default()
+            }
+            Err(e) => format!("error: {}", e),
+        };
+        if w.write_all(format!("{}\n", answer).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// The next request, or never without a control socket.
+pub async fn next(rx: &Option<Receiver<Request>>) -> Request {
+    match rx {
+        Some(rx) => match rx.recv().await {
+            Ok(req) => req,
+            Err(_) => std::future::pending().await,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait out a snooze while serving requests, which may extend or end it.
+pub async fn snooze(rx: &Option<Receiver<Request>>, d: Duration) {
+    let mut until = Instant::now() + d;
+    loop {
+        let left = until.saturating_duration_since(Instant::now());
+        let req = match select(Box::pin(async_std::task::sleep(left)), Box::pin(next(rx))).await {
+            Either::Left(_) => return,
+            Either::Right((req, _)) => req,
+        };
+        match req.cmd {
+            Command::Snooze(d) => {
+                until = Instant::now() + d;
+                req.reply("snoozed");
+            }
+            Command::Resume => {
+                req.reply("resumed");
+                return;
+            }
+            Command::Uids | Command::ArchiveRead => req.reply("error: snoozed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Command;
+    use async_std::io::BufReader;
+    use async_std::os::unix::net::UnixStream;
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(Command::Snooze(Duration::from_secs(1800))),
+            "snooze 30".parse()
+        );
+        assert_eq!(Ok(Command::Resume), " resume\n".parse::<Command>());
+        assert_eq!(Ok(Command::Uids), "uids".parse::<Command>());
+        assert_eq!(Ok(Command::ArchiveRead), "archive-read".parse::<Command>());
+        assert!("snooze".parse::<Command>().is_err());
+        assert!("snooze soon".parse::<Command>().is_err());
+        assert!("snooze 200000000000000000".parse::<Command>().is_err());
+        assert!("snooze 10081".parse::<Command>().is_err());
+        assert!("frobnicate".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control");
+        block_on(async {
+            let rx = Some(super::listen(path.as_path().into()).await.unwrap());
+            let stream = UnixStream::connect(&path).await.unwrap();
+            let mut lines = BufReader::new(stream.clone()).lines();
+            let mut w = stream;
+
+            w.write_all(b"bogus\nsnooze 5\n").await.unwrap();
+            let answer = lines.next().await.unwrap().unwrap();
+            assert!(answer.starts_with("error: "), "{}", answer);
+            let req = super::next(&rx).await;
+            assert_eq!(Command::Snooze(Duration::from_secs(300)), req.cmd);
+            req.reply("snoozed");
+            assert_eq!("snoozed", lines.next().await.unwrap().unwrap());
+
+            // Ended early from another request.
+            w.write_all(b"resume\n").await.unwrap();
+            super::snooze(&rx, Duration::from_secs(60)).await;
+            assert_eq!("resumed", lines.next().await.unwrap().unwrap());
+        });
+    }
+}
@@ -1 +1,136 @@
 pub type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// What stops the program for good, rather than being retried: each kind
+/// exits with its own status.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fatal {
+    /// Flags, files or local resources that can't work as given.
+    Config(String),
+    /// The server refused the credentials, or has no way to take them.
+    Auth(String),
+    /// The server lacks what was asked of it, such as a folder or extension.
+    Mailbox(String),
+    /// The server failing in a way reconnecting won't fix.
+    Server(String),
+}
+
+impl Fatal {
+    /// 1 for what the user has to change, 2 for the server or credentials.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Fatal::Config(_) | Fatal::Mailbox(_) => 1,
+            Fatal::Auth(_) | Fatal::Server(_) => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (Fatal::Config(msg) | Fatal::Auth(msg) | Fatal::Mailbox(msg) | Fatal::Server(msg)) =
+            self;
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// The response code, such as `UNAVAILABLE`, of a NO or BAD reply.
+///
+/// The RFC 5530 codes aren't parsed by imap-proto, so they get left at the
+/// start of the human readable text.
+pub fn response_code(e: &async_imap::error::Error) -> Option<String> {
+    use async_imap::error::Error;
+    let (Error::No(msg) | Error::Bad(msg)) = e else {
+        return None;
+    };
+    let info = &msg[msg.find("info: ")?..];
+    let code = &info[info.find('[')? + 1..];
+    let end = code.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))?;
+    (end > 0).then(|| code[..end].to_ascii_uppercase())
+}
+
+/// Whether an error is the server turning us away for having too many
+/// connections open, by RFC 5530's LIMIT code or the usual wording of servers
+/// without it.
+pub fn connection_limit(msg: &str) -> bool {
+    let msg = msg.to_ascii_lowercase();
+    msg.contains("[limit]") || msg.contains("too many connections")
+}
+
+/// Whether the connection dropped under us, such as a TLS stream cut short
+/// by a network stutter, rather than the server refusing anything.
+pub fn transient(e: &async_imap::error::Error) -> bool {
+    use async_imap::error::Error;
+    use std::io::ErrorKind;
+    match e {
+        Error::ConnectionLost => true,
+        Error::Io(e) => match e.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => true,
+            // TLS failures come wrapped, going by the OpenSSL wording.
+            ErrorKind::Other => {
+                let msg = e.to_string().to_ascii_lowercase();
+                msg.contains("unexpected eof") || msg.contains("renegotiat")
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_imap::error::Error;
+    use std::io::{self, ErrorKind};
+
+    #[test]
+    fn test_fatal() {
+        let e = super::Fatal::Auth("Failure logging in: bad password".into());
+        assert_eq!(2, e.exit_code());
+        assert_eq!("Failure logging in: bad password", e.to_string());
+        assert_eq!(
+            1,
+            super::Fatal::Mailbox("No mailbox Work".into()).exit_code()
+        );
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        assert!(super::connection_limit(
+            "No Response: code: None, info: Some(\"[LIMIT] Too many\")"
+        ));
+        assert!(super::connection_limit(
+            "server said BYE: Too many connections from your IP"
+        ));
+        assert!(!super::connection_limit("server said BYE: shutting down"));
+    }
+
+    #[test]
+    fn test_transient() {
+        assert!(super::transient(&Error::ConnectionLost));
+        let e = io::Error::new(ErrorKind::ConnectionReset, "reset by peer");
+        assert!(super::transient(&Error::Io(e)));
+        let e = io::Error::other("error:0A000126:SSL routines::unexpected eof while reading");
+        assert!(super::transient(&Error::Io(e)));
+        let e = io::Error::other("certificate verify failed");
+        assert!(!super::transient(&Error::Io(e)));
+        assert!(!super::transient(&Error::Bad("nope".into())));
+    }
+
+    #[test]
+    fn test_response_code() {
+        let e = Error::No("code: None, info: Some(\"[UNAVAILABLE] Try later\")".into());
+        assert_eq!(Some("UNAVAILABLE"), super::response_code(&e).as_deref());
+
+        let e = Error::Bad("code: None, info: Some(\"[overquota] Full\")".into());
+        assert_eq!(Some("OVERQUOTA"), super::response_code(&e).as_deref());
+
+        let e = Error::No("code: None, info: Some(\"Mailbox doesn't exist\")".into());
+        assert_eq!(None, super::response_code(&e));
+
+        assert_eq!(None, super::response_code(&Error::ConnectionLost));
+    }
+}
@@ -0,0 +1,255 @@
+/// Substitute `{name}` placeholders in a template with their matching value.
+///
+/// Placeholders without a matching value are kept verbatim.
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let name = &rest[1..end];
+        match values.iter().find(|(k, _)| *k == name) {
+            Some((_, v)) => out.push_str(v),
+            None => out.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The names of the `{name}` placeholders of a template, as `render` finds
+/// them.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        names.push(&rest[1..end]);
+        rest = &rest[end + 1..];
+    }
+    names
+}
+
+/// Decode the RFC 2047 encoded words of a header, such as
+/// `=?UTF-8?Q?Caf=C3=A9?=`. Words in charsets other than UTF-8, ASCII and
+/// Latin-1 are kept as they are.
+pub fn decode_header(raw: &[u8]) -> String {
+    let raw = String::from_utf8_lossy(raw);
+    let mut out = String::new();
+    let mut rest = raw.as_ref();
+    // Whitespace between two encoded words is dropped.
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let word = rest[start + 2..].splitn(4, '?').collect::<Vec<_>>();
+        let decoded = match word.as_slice() {
+            [charset, enc, text, tail] if tail.starts_with('=') => decode_word(charset, enc, text)
+                .map(|d| (d, charset.len() + enc.len() + text.len() + 6)),
+            _ => None,
+        };
+        let Some((decoded, len)) = decoded else {
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            after_word = false;
+            continue;
+        };
+        let between = &rest[..start];
+        if !(after_word && between.trim().is_empty()) {
+            out.push_str(between);
+        }
+        out.push_str(&decoded);
+        rest = &rest[start + len..];
+        after_word = true;
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The text of one encoded word, if its charset and encoding are known.
+fn decode_word(charset: &str, enc: &str, text: &str) -> Option<String> {
+    let bytes = match enc {
+        "B" | "b" => base64(text)?,
+        "Q" | "q" => {
+            let mut bytes = Vec::new();
+            let mut it = text.bytes();
+            while let Some(b) = it.next() {
+                match b {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [it.next()?, it.next()?];
+                        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                    }
+                    b => bytes.push(b),
+                }
+            }
+            bytes
+        }
+        _ => return None,
+    };
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" | "us-ascii" => Some(String::from_utf8_lossy(&bytes).into()),
+        "iso-8859-1" | "latin1" => Some(bytes.iter().map(|&b| b as char).collect()),
+        _ => None,
+    }
+}
+
+/// Standard base64, padded or not.
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().take_while(|&c| c != b'=') {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Compact rendering of an elapsed time, such as `42s` or `5m`.
+pub fn age(d: std::time::Duration) -> String {
+    match d.as_secs() {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s => format!("{}h", s / 3600),
+    }
+}
+
+/// Local time of day, such as `14:03:22`.
+pub fn clock(t: std::time::SystemTime) -> String {
+    let secs = t
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()) as libc::time_t;
+    // Safety: both pointers are to locals, tm is filled on success.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return String::new();
+    }
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Signed rendering of a count change, such as `+1` or `-2`; empty when
+/// nothing changed.
+pub fn delta(d: i64) -> String {
+    match d {
+        0 => String::new(),
+        d => format!("{:+}", d),
+    }
+}
+
+/// Cut `s` to at most `max` characters, ending with an ellipsis when cut.
+pub fn truncate(s: String, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s;
+    }
+    let mut out: String = s.chars().take(max.saturating_sub(1)).collect();
+    if max > 0 {
+        out.push('…');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_plain() {
+        assert_eq!("no tokens", super::render("no tokens", &[]));
+    }
+
+    #[test]
+    fn test_substitute() {
+        let values = [("new", "3".to_string()), ("total", "42".to_string())];
+        assert_eq!("(3) 42", super::render("({new}) {total}", &values));
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(
+            vec!["new", "total"],
+            super::placeholders("({new}) {total} {unclosed")
+        );
+        assert!(super::placeholders("no tokens").is_empty());
+    }
+
+    #[test]
+    fn test_unknown_kept() {
+        let values = [("new", "3".to_string())];
+        assert_eq!("3 {nope} {", super::render("{new} {nope} {", &values));
+    }
+
+    #[test]
+    fn test_decode_header() {
+        assert_eq!("plain", super::decode_header(b"plain"));
+        assert_eq!("Café", super::decode_header(b"=?UTF-8?Q?Caf=C3=A9?="));
+        assert_eq!(
+            "Re: Café",
+            super::decode_header(b"Re: =?utf-8?B?Q2Fmw6k=?=")
+        );
+        assert_eq!(
+            "Grüße aus Köln",
+            super::decode_header(b"=?ISO-8859-1?Q?Gr=FC=DFe_aus?= =?ISO-8859-1?Q?_K=F6ln?=")
+        );
+        assert_eq!(
+            "=?koi8-r?B?8NLJ?=",
+            super::decode_header(b"=?koi8-r?B?8NLJ?=")
+        );
+        assert_eq!("huh =? no", super::decode_header(b"huh =? no"));
+    }
+
+    #[test]
+    fn test_clock() {
+        let t = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = super::clock(t);
+        assert_eq!(8, clock.len());
+        let parts: Vec<u32> = clock.split(':').map(|p| p.parse().unwrap()).collect();
+        assert!(parts[0] < 24 && parts[1] < 60 && parts[2] < 60);
+        // 22:13:20 UTC, zones being some quarters of an hour away.
+        assert_eq!(13, parts[1] % 15);
+        assert_eq!(20, parts[2]);
+    }
+
+    #[test]
+    fn test_age() {
+        assert_eq!("0s", super::age(Duration::from_millis(300)));
+        assert_eq!("59s", super::age(Duration::from_secs(59)));
+        assert_eq!("1m", super::age(Duration::from_secs(60)));
+        assert_eq!("59m", super::age(Duration::from_secs(3599)));
+        assert_eq!("2h", super::age(Duration::from_secs(7300)));
+    }
+
+    #[test]
+    fn test_delta() {
+        assert_eq!("", super::delta(0));
+        assert_eq!("+1", super::delta(1));
+        assert_eq!("-12", super::delta(-12));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!("(3) 42", super::truncate("(3) 42".into(), 6));
+        assert_eq!("(3) …", super::truncate("(3) 42".into(), 5));
+        assert_eq!("✉ é…", super::truncate("✉ éàü".into(), 4));
+        assert_eq!("", super::truncate("(3) 42".into(), 0));
+    }
+}
@@ -0,0 +1,134 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_std::io::{Read, Write};
+
+/// The fetch attribute of Gmail conversation ids, which the response parser
+/// rejects, failing the whole connection.
+const THRID: &[u8] = b"X-GM-THRID";
+/// Its message id twin, of the same length and syntax, which the parser
+/// knows.
+const MSGID: &[u8] = b"X-GM-MSGID";
+
+/// A stream reading `X-GM-THRID` from the server as `X-GM-MSGID`, so that
+/// conversation ids come through as message ids.
+///
+/// Only when `enabled`, as message contents fetched would be rewritten too.
+#[derive(Debug)]
+pub struct ThreadIds<T> {
+    inner: T,
+    enabled: bool,
+    /// The end of the last read, maybe the start of an attribute name.
+    held: Vec<u8>,
+}
+
+impl<T> ThreadIds<T> {
+    pub fn new(inner: T, enabled: bool) -> ThreadIds<T> {
+        ThreadIds {
+            inner,
+            enabled,
+            held: Vec::new(),
+        }
+    }
+}
+
+/// Rewrite the attribute names in `data`, returning the length of its end
+/// that may start one.
+fn rewrite(data: &mut [u8]) -> usize {
+    let mut i = 0;
+    while i + THRID.len() <= data.len() {
+        if &data[i..i + THRID.len()] == THRID {
+            data[i..i + THRID.len()].copy_from_slice(MSGID);
+            i += THRID.len();
+        } else {
+            i += 1;
+        }
+    }
+    (1..THRID.len())
+        .rev()
+        .find(|&n| n <= data.len() && data.ends_with(&THRID[..n]))
+        .unwrap_or(0)
+}
+
+impl<T: Read + Unpin> Read for ThreadIds<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            let held = this.held.len();
+            if buf.len() <= held {
+                // Too small to look further, given as is.
+                let n = buf.len();
+                buf.copy_from_slice(&this.held[..n]);
+                this.held.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            buf[..held].copy_from_slice(&this.held);
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[held..]))?;
+            this.held.clear();
+            if n == 0 {
+                return Poll::Ready(Ok(held));
+            }
+            let total = held + n;
+            let tail = rewrite(&mut buf[..total]);
+            this.held.extend_from_slice(&buf[total - tail..total]);
+            if tail < total {
+                return Poll::Ready(Ok(total - tail));
+            }
+        }
+    }
+}
+
+impl<T: Write + Unpin> Write for ThreadIds<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_std::io::{BufReader, Cursor};
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_rewrite() {
+        let mut data = b"* 1 FETCH (X-GM-THRID 42 UID 7)\r\n* 2 FETCH (X-GM".to_vec();
+        assert_eq!(4, super::rewrite(&mut data));
+        assert!(data.starts_with(b"* 1 FETCH (X-GM-MSGID 42 UID 7)"));
+        assert_eq!(0, super::rewrite(&mut b"X-GM-THRID".to_vec()));
+        assert_eq!(0, super::rewrite(&mut Vec::new()));
+    }
+
+    #[test]
+    fn test_split_reads() {
+        block_on(async {
+            let server = Cursor::new(b"* 1 FETCH (X-GM-THRID 42)\r\n".to_vec());
+            // Reads of 3 bytes from the server, splitting the name.
+            let mut s = super::ThreadIds::new(BufReader::with_capacity(3, server), true);
+            let mut got = String::new();
+            s.read_to_string(&mut got).await.unwrap();
+            assert_eq!("* 1 FETCH (X-GM-MSGID 42)\r\n", got);
+        });
+    }
+}
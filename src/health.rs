@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::net::TcpListener;
+use async_std::prelude::*;
+use async_std::task::spawn;
+
+use crate::errors::Res;
+
+/// The HTTP answer to any request: 200 while the last successful poll is
+/// within `max_age`, 503 past it.
+fn response(age: Duration, max_age: Duration) -> String {
+    let (status, body) = match age <= max_age {
+        true => ("200 OK", format!("ok, last poll {}s ago\n", age.as_secs())),
+        false => (
+            "503 Service Unavailable",
+            format!("stale, last poll {}s ago\n", age.as_secs()),
+        ),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Answer health checks on `addr`, whatever the path, going by the
+/// watchdog's last success. Returns the address listened on.
+pub async fn listen(
+    addr: SocketAddr,
+    last: Arc<Mutex<Instant>>,
+    max_age: Duration,
+) -> Res<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Failure accepting health check: {}", e);
+                    continue;
+                }
+            };
+            let age = last.lock().unwrap().elapsed();
+            spawn(async move {
+                // The request itself doesn't matter, only wait for it.
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response(age, max_age).as_bytes()).await;
+            });
+        }
+    });
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_std::net::TcpStream;
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_response() {
+        let r = super::response(Duration::from_secs(5), Duration::from_secs(60));
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"), "{}", r);
+        assert!(r.ends_with("\r\n\r\nok, last poll 5s ago\n"), "{}", r);
+        let r = super::response(Duration::from_secs(61), Duration::from_secs(60));
+        assert!(r.starts_with("HTTP/1.1 503 "), "{}", r);
+    }
+
+    #[test]
+    fn test_listen() {
+        block_on(async {
+            let last = Arc::new(Mutex::new(Instant::now()));
+            let addr = super::listen(
+                "127.0.0.1:0".parse().unwrap(),
+                last,
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /healthz HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer).await.unwrap();
+            assert!(answer.starts_with("HTTP/1.1 200 OK"), "{}", answer);
+        });
+    }
+}
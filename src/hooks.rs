@@ -0,0 +1,44 @@
+use std::process::{Command, Stdio};
+
+/// Start a user command through the shell without waiting for it, passing
+/// `args` as its positional parameters `$1`, `$2`... Failures are logged
+/// only, a hook never stops the polling.
+pub fn spawn(cmd: &str, args: &[&str]) {
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(cmd)
+        .arg("imap_block")
+        .args(args)
+        .stdin(Stdio::null())
+        // Stdout is the bar's, keep it clean.
+        .stdout(Stdio::null())
+        .spawn();
+    match child {
+        // Reaped in the background, not to leave zombies.
+        Ok(mut child) => {
+            async_std::task::spawn_blocking(move || child.wait());
+        }
+        Err(e) => log::warn!("Failure running hook {:?}: {}", cmd, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_spawn() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let cmd = format!("echo \"$1 $2\" > {}.tmp && mv {0}.tmp {0}", out.display());
+        super::spawn(&cmd, &["connected", "imap.example.com"]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !out.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let got = std::fs::read_to_string(&out).unwrap();
+        assert_eq!("connected imap.example.com\n", got);
+    }
+}
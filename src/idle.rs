@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+
+use async_imap::error::Error;
+use async_imap::extensions::idle::{Handle, IdleResponse};
+use async_imap::imap_proto::{Response, Status};
+use async_imap::types::UnsolicitedResponse;
+use async_imap::Session;
+use async_std::io::{Read, Write};
+use async_std::task::sleep;
+use futures::future::{select, Either};
+use std::future::Future;
+
+/// Whether an untagged response received while idle may change the counts:
+/// new or expunged messages, and flag updates such as \Seen set elsewhere.
+fn changes_counts(r: &Response) -> bool {
+    match r {
+        Response::MailboxData(_)
+        | Response::Fetch(..)
+        | Response::Expunge(_)
+        | Response::Vanished { .. } => true,
+        // Nothing to count anymore, polling gets the error across.
+        Response::Data {
+            status: Status::Bye,
+            ..
+        } => true,
+        _ => false,
+    }
+}
+
+/// Whether a response left over by a command, such as NOOP, may change the
+/// counts.
+fn unsolicited_changes_counts(r: &UnsolicitedResponse) -> bool {
+    match r {
+        UnsolicitedResponse::Exists(_)
+        | UnsolicitedResponse::Recent(_)
+        | UnsolicitedResponse::Expunge(_) => true,
+        UnsolicitedResponse::Other(data) => changes_counts(data.parsed()),
+        _ => false,
+    }
+}
+
+/// What ended an IDLE wait.
+#[derive(Debug, PartialEq)]
+pub enum Wake<O> {
+    /// The server pushed something that may have changed the counts.
+    Changes,
+    /// The keep-alive passed with nothing of note pushed.
+    Quiet,
+    /// The interrupt completed, with its output.
+    Interrupt(O),
+}
+
+/// Idle until something may have changed the counts, `keep_alive` passed, or
+/// `interrupt` completed.
+///
+/// Changes are collected for `debounce` after the first, so that a burst
+/// of arrivals makes one poll; they are reported once it passes, or the
+/// keep-alive, whichever comes first.
+///
+/// An interrupt triggers the stopper rather than dropping the IDLE wait, so
+/// the wait returns at once with no response left half read, and the handle
+/// is ready for DONE and LOGOUT.
+pub async fn wait<T, F>(
+    idle: &mut Handle<T>,
+    keep_alive: Duration,
+    debounce: Duration,
+    interrupt: F,
+) -> Result<Wake<F::Output>, Error>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+    F: Future,
+{
+    let mut deadline = Instant::now() + keep_alive;
+    let mut changed = false;
+    let mut interrupt = Box::pin(interrupt);
+    loop {
+        let (fut, stopper) =
+            idle.wait_with_timeout(deadline.saturating_duration_since(Instant::now()));
+        let mut fut = Box::pin(fut);
+        let res = match select(fut.as_mut(), interrupt.as_mut()).await {
+            Either::Left((res, _)) => res,
+            Either::Right((out, _)) => {
+                drop(stopper);
+                fut.await?;
+                return Ok(Wake::Interrupt(out));
+            }
+        };
+        drop(stopper); // drop only after waiting to avoid early return
+        match res? {
+            IdleResponse::NewData(data) if changes_counts(data.parsed()) => {
+                log::debug!("woken by {:?}", data.parsed());
+                let bye = matches!(
+                    data.parsed(),
+                    Response::Data {
+                        status: Status::Bye,
+                        ..
+                    }
+                );
+                if debounce.is_zero() || bye {
+                    return Ok(Wake::Changes);
+                }
+                if !changed {
+                    log::debug!("waiting {}ms for more changes", debounce.as_millis());
+                    changed = true;
+                    deadline = deadline.min(Instant::now() + debounce);
+                }
+            }
+            IdleResponse::NewData(data) => log::debug!("ignoring {:?}", data.parsed()),
+            IdleResponse::Timeout if changed => return Ok(Wake::Changes),
+            IdleResponse::Timeout => return Ok(Wake::Quiet),
+            // Not ours, the stopper is only dropped once the wait is over.
+            IdleResponse::ManualInterrupt => return Ok(Wake::Changes),
+        }
+    }
+}
+
+/// Wait `period` on a server without IDLE, sending a NOOP every `every`
+/// for the connection not to be dropped as idle, until one gets news that
+/// may change the counts, or `interrupt` completed.
+pub async fn noop_wait<T, F>(
+    s: &mut Session<T>,
+    period: Duration,
+    every: Duration,
+    interrupt: F,
+) -> Result<Wake<F::Output>, Error>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+    F: Future,
+{
+    // Left over by the poll, already counted.
+    while s.unsolicited_responses.try_recv().is_ok() {}
+    let deadline = Instant::now() + period;
+    let mut interrupt = Box::pin(interrupt);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let nap = Box::pin(sleep(left.min(every)));
+        if let Either::Right((out, _)) = select(nap, interrupt.as_mut()).await {
+            return Ok(Wake::Interrupt(out));
+        }
+        if left <= every {
+            // Polled next anyway.
+            return Ok(Wake::Quiet);
+        }
+        s.noop().await?;
+        let mut changed = false;
+        while let Ok(r) = s.unsolicited_responses.try_recv() {
+            changed |= unsolicited_changes_counts(&r);
+        }
+        if changed {
+            log::debug!("NOOP got changes");
+            return Ok(Wake::Changes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_imap::imap_proto::Response;
+
+    fn changes_counts(line: &[u8]) -> bool {
+        super::changes_counts(&Response::from_bytes(line).unwrap().1)
+    }
+
+    #[test]
+    fn test_changes_counts() {
+        assert!(changes_counts(b"* 4 EXISTS\r\n"));
+        assert!(changes_counts(b"* 2 EXPUNGE\r\n"));
+        assert!(changes_counts(b"* 3 FETCH (FLAGS (\\Seen))\r\n"));
+        assert!(changes_counts(b"* FLAGS (\\Seen \\Flagged)\r\n"));
+        assert!(changes_counts(b"* BYE shutting down\r\n"));
+        assert!(!changes_counts(b"* CAPABILITY IMAP4rev1 IDLE\r\n"));
+        assert!(!changes_counts(b"* NO [ALERT] disk almost full\r\n"));
+    }
+
+    #[test]
+    fn test_unsolicited_changes_counts() {
+        use async_imap::types::UnsolicitedResponse;
+        assert!(super::unsolicited_changes_counts(
+            &UnsolicitedResponse::Exists(4)
+        ));
+        assert!(super::unsolicited_changes_counts(
+            &UnsolicitedResponse::Expunge(2)
+        ));
+        assert!(!super::unsolicited_changes_counts(
+            &UnsolicitedResponse::Status {
+                mailbox: "INBOX".into(),
+                attributes: Vec::new(),
+            }
+        ));
+    }
+}
@@ -0,0 +1,27 @@
+//! Connection, polling and output logic of the imap_block status command.
+
+pub mod accounts;
+pub mod auth;
+pub mod backoff;
+pub mod caps;
+pub mod config;
+pub mod connector;
+pub mod control;
+pub mod creds;
+pub mod errors;
+pub mod format;
+pub mod gmail;
+pub mod health;
+pub mod hooks;
+pub mod idle;
+pub mod logging;
+pub mod notify;
+pub mod output;
+pub mod preauth;
+pub mod proxy;
+pub mod session;
+pub mod signals;
+pub mod state;
+pub mod watchdog;
+#[cfg(feature = "websocket")]
+pub mod ws;
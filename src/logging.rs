@@ -0,0 +1,124 @@
+use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The env_logger default
+    Text,
+    /// One JSON object per line, for journald and the like
+    Json,
+}
+
+/// Least severe messages logged, from `--log-level`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> log::LevelFilter {
+        match level {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+/// Server name added to JSON lines, once known.
+static HOST: OnceLock<String> = OnceLock::new();
+
+pub fn set_host(host: &str) {
+    let _ = HOST.set(host.into());
+}
+
+/// A structured log line. Only the message and server name are recorded,
+/// neither of which ever holds credentials.
+#[derive(serde::Serialize)]
+struct Line<'a> {
+    ts: String,
+    level: &'a str,
+    target: &'a str,
+    event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<&'a str>,
+}
+
+fn json_line(ts: String, record: &log::Record) -> String {
+    serde_json::to_string(&Line {
+        ts,
+        level: record.level().as_str(),
+        target: record.target(),
+        event: record.args().to_string(),
+        host: HOST.get().map(|h| h.as_str()),
+    })
+    .unwrap()
+}
+
+/// Set up the logger; `level` overrides RUST_LOG when given.
+///
+/// Lines are appended to `file` instead of stderr when given; one that
+/// cannot be opened is reported on stderr.
+pub fn init(level: Option<log::LevelFilter>, format: LogFormat, file: Option<&Path>) {
+    let mut builder = match level {
+        Some(level) => {
+            let mut b = env_logger::Builder::new();
+            b.filter_level(level);
+            b
+        }
+        None => env_logger::Builder::from_default_env(),
+    };
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let ts = buf.timestamp().to_string();
+            writeln!(buf, "{}", json_line(ts, record))
+        });
+    }
+    let mut failed = None;
+    if let Some(path) = file {
+        match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            Ok(f) => {
+                builder.target(env_logger::Target::Pipe(Box::new(f)));
+            }
+            Err(e) => failed = Some(format!("{}: {}", path.display(), e)),
+        }
+    }
+    builder.init();
+    if let Some(e) = failed {
+        log::error!("Cannot open log file {}, logging to stderr", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_json_line() {
+        let line = super::json_line(
+            "2024-01-01T00:00:00Z".into(),
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("imap_block")
+                .args(format_args!("say \"hi\""))
+                .build(),
+        );
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("WARN", v["level"]);
+        assert_eq!("imap_block", v["target"]);
+        assert_eq!("say \"hi\"", v["event"]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+
+use crate::format;
+
+/// Default `--notify-format`.
+pub const FORMAT: &str = "{count} new in {folder}";
+
+/// Folders whose new count went up since the previous poll, with by how
+/// much. Folders the previous poll didn't count are left out, not to
+/// announce a whole folder just added.
+pub fn increases(
+    prev: &BTreeMap<String, usize>,
+    cur: &BTreeMap<String, usize>,
+) -> Vec<(String, usize)> {
+    cur.iter()
+        .filter_map(|(folder, &n)| {
+            let before = *prev.get(folder)?;
+            (n > before).then(|| (folder.clone(), n - before))
+        })
+        .collect()
+}
+
+/// The body of one notification covering all `increases` of a poll, a line
+/// per folder from the `--notify-format` template.
+pub fn message(template: &str, increases: &[(String, usize)]) -> Option<String> {
+    if increases.is_empty() {
+        return None;
+    }
+    let lines: Vec<String> = increases
+        .iter()
+        .map(|(folder, n)| {
+            format::render(
+                template,
+                &[
+                    ("folder", crate::session::decode_utf7(folder)),
+                    ("count", n.to_string()),
+                ],
+            )
+        })
+        .collect();
+    Some(lines.join("\n"))
+}
+
+/// Pop up a desktop notification through `notify-send`, without waiting for
+/// it. Failures are logged only.
+pub fn send(body: &str) {
+    let child = Command::new("notify-send")
+        .arg("--app-name=imap_block")
+        .arg("New mail")
+        .arg(body)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            async_std::task::spawn_blocking(move || child.wait());
+        }
+        Err(e) => log::warn!("Failure sending notification: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+
+    fn counts(c: &[(&str, usize)]) -> BTreeMap<String, usize> {
+        c.iter().map(|(f, n)| (f.to_string(), *n)).collect()
+    }
+
+    #[test]
+    fn test_increases() {
+        let prev = counts(&[("INBOX", 2), ("Work", 1), ("Lists", 5)]);
+        let cur = counts(&[("INBOX", 2), ("Work", 3), ("Lists", 4), ("New", 9)]);
+        assert_eq!(vec![("Work".to_string(), 2)], super::increases(&prev, &cur));
+    }
+
+    #[test]
+    fn test_message() {
+        assert_eq!(None, super::message("{count} new in {folder}", &[]));
+        let inc = [("INBOX".to_string(), 1), ("Entw&APw-rfe".to_string(), 2)];
+        assert_eq!(
+            Some("1 new in INBOX\n2 new in Entwürfe"),
+            super::message("{count} new in {folder}", &inc).as_deref()
+        );
+    }
+}
@@ -0,0 +1,1470 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::format;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    I3,
+    /// i3bar protocol, with the fields swaybar renders differently made explicit
+    Sway,
+    Waybar,
+    /// Only the unread count, as a bare integer
+    Count,
+    /// The formatted text alone, for tmux and dwmblocks
+    Plain,
+}
+
+/// Where status lines go instead of stdout, from `--output`.
+static OUTPUT: OnceLock<PathBuf> = OnceLock::new();
+/// The `--output` FIFO, while a reader keeps it open.
+static FIFO: Mutex<Option<File>> = Mutex::new(None);
+
+pub fn set_output(path: PathBuf) {
+    let _ = OUTPUT.set(path);
+}
+
+/// Write a status line to stdout, or to the `--output` path.
+fn emit(line: &str) {
+    let Some(path) = OUTPUT.get() else {
+        println!("{}", line);
+        return;
+    };
+    if let Err(e) = write_to(path, line) {
+        log::debug!("Status not written to {}: {}", path.display(), e);
+    }
+}
+
+/// Replace the contents of a file with `line`, or append it to a FIFO.
+///
+/// A FIFO is opened without blocking, then kept open: with no reader the line
+/// is dropped rather than waiting for one.
+fn write_to(path: &Path, line: &str) -> std::io::Result<()> {
+    let fifo = std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo());
+    if !fifo {
+        return replace(path, line);
+    }
+    let mut f = FIFO.lock().unwrap();
+    let file = match &mut *f {
+        Some(file) => file,
+        None => f.insert(
+            OpenOptions::new()
+                .append(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)?,
+        ),
+    };
+    let res = writeln!(file, "{}", line);
+    if res.is_err() {
+        // The reader went away, wait for the next one.
+        *f = None;
+    }
+    res
+}
+
+/// Replace the contents of a file with `line`, through a rename so that
+/// readers never see it half written.
+fn replace(path: &Path, line: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, format!("{}\n", line))?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Write the unread count alone to the `--badge-file`, for dock badges.
+pub fn write_badge(path: &Path, count: usize) {
+    if let Err(e) = replace(path, &count.to_string()) {
+        log::warn!("Badge not written to {}: {}", path.display(), e);
+    }
+}
+
+/// The `--status-file` contents, for supervisors to scrape.
+#[derive(Serialize)]
+struct StatusFile<'a> {
+    connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<&'a str>,
+    /// Unix time of the last successful poll, none before the first.
+    checked_at: Option<u64>,
+    unseen: Option<usize>,
+    total: Option<u32>,
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Write the connection state and the counts of the last successful poll,
+/// if any, to the `--status-file`, as JSON.
+pub fn write_status_file(
+    path: &Path,
+    host: Option<&str>,
+    conn: Connection,
+    status: Option<&Status>,
+) {
+    let contents = StatusFile {
+        connected: conn == Connection::Connected,
+        host,
+        checked_at: status.map(|s| unix_secs(s.checked_at)),
+        unseen: status.map(|s| s.new_count),
+        total: status.map(|s| s.count),
+    };
+    let json = serde_json::to_string(&contents).unwrap();
+    if let Err(e) = replace(path, &json) {
+        log::warn!("Status not written to {}: {}", path.display(), e);
+    }
+}
+
+/// How the `percentage` field of a block is computed, for bars drawing it
+/// as a progress indicator.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PercentMode {
+    /// Unread messages out of all messages
+    Ratio,
+    /// 100 if anything is unread, else 0
+    Any,
+}
+
+impl PercentMode {
+    fn percent(&self, status: &Status) -> u32 {
+        match self {
+            PercentMode::Ratio if status.count == 0 => 0,
+            PercentMode::Ratio => {
+                (status.new_count as u64 * 100 / status.count as u64).min(100) as u32
+            }
+            PercentMode::Any if status.new_count > 0 => 100,
+            PercentMode::Any => 0,
+        }
+    }
+}
+
+/// Block colors by unread count, from `--color-buckets` such as
+/// `5:#00cc00,20:#cccc00,:#cc0000`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColorBuckets(Vec<ColorBucket>);
+
+/// The color of counts up to `max`, or any count when it is absent.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColorBucket {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>,
+    pub color: String,
+}
+
+impl ColorBuckets {
+    /// The color of the first bucket holding `count`, if any.
+    fn color(&self, count: usize) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|b| b.max.is_none_or(|max| count <= max))
+            .map(|b| b.color.as_str())
+    }
+}
+
+impl std::str::FromStr for ColorBuckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ColorBuckets, String> {
+        let mut buckets: Vec<ColorBucket> = Vec::new();
+        for spec in s.split(',') {
+            let Some((max, color)) = spec.split_once(':') else {
+                return Err(format!("expected max:color, got {:?}", spec));
+            };
+            if color.is_empty() {
+                return Err(format!("missing color in {:?}", spec));
+            }
+            let max = match max {
+                "" => None,
+                max => Some(
+                    max.parse()
+                        .map_err(|_| format!("invalid count {:?} in {:?}", max, spec))?,
+                ),
+            };
+            match buckets.last().map(|b| b.max) {
+                Some(None) => return Err("only the last bucket may leave out its count".into()),
+                Some(Some(prev)) if max.is_some_and(|m| m <= prev) => {
+                    return Err(format!("counts must increase, {:?} doesn't", spec))
+                }
+                _ => (),
+            }
+            buckets.push(ColorBucket {
+                max,
+                color: color.into(),
+            });
+        }
+        Ok(ColorBuckets(buckets))
+    }
+}
+
+/// Built-in tokens of the format template.
+pub const TOKENS: &[&str] = &[
+    "new", "total", "recent", "junk", "keyword", "flagged", "quota", "age", "delta",
+];
+
+/// Check that a template has only built-in tokens, or those in `extra`, so
+/// that a typo isn't shown as is.
+pub fn check_format(template: &str, extra: &[&str]) -> Result<(), String> {
+    match format::placeholders(template)
+        .into_iter()
+        .find(|name| !TOKENS.contains(name) && !extra.contains(name))
+    {
+        Some(name) => Err(format!("unknown token {{{}}} in {:?}", name, template)),
+        None => Ok(()),
+    }
+}
+
+/// Counts gathered by a poll, as rendered in the status block.
+pub struct Status {
+    pub new_count: usize,
+    pub count: u32,
+    /// Messages flagged \Recent, as reported by EXAMINE.
+    pub recent: u32,
+    /// Unread count of the junk folder, when one is watched.
+    pub junk: Option<usize>,
+    /// Messages with the `--keyword` keyword, unless the server rejected it.
+    pub keyword: Option<usize>,
+    /// Messages flagged \Flagged, when the format shows them.
+    pub flagged: Option<usize>,
+    /// Counts of the `--watch` counters, by token name.
+    pub watched: Vec<(String, usize)>,
+    /// Block color of the best matching color rule, if any matched.
+    pub color: Option<String>,
+    /// UIDs of the messages counted as new, by folder, when asked for.
+    pub uids: Option<BTreeMap<String, Vec<u32>>>,
+    /// New count of each watched folder, for notifications.
+    pub by_folder: BTreeMap<String, usize>,
+    /// Percentage of the mailbox quota used, on servers with QUOTA.
+    pub quota: Option<u32>,
+    /// Sender and subject of the newest new messages, when asked for.
+    pub previews: Vec<String>,
+    /// Change of the unread count against a baseline, for the {delta} token.
+    pub delta: i64,
+    /// When the last examine/search completed, for the {age} token.
+    pub last_success: Instant,
+    /// The same on the wall clock, for the time shown by `show_timestamp`.
+    pub checked_at: SystemTime,
+    /// Hover text, where the bar supports it.
+    pub tooltip: Option<String>,
+}
+
+impl Status {
+    /// Only counts, of a poll just done.
+    pub fn new(new_count: usize, count: u32) -> Status {
+        Status {
+            new_count,
+            count,
+            recent: 0,
+            junk: None,
+            keyword: None,
+            flagged: None,
+            watched: Vec::new(),
+            color: None,
+            uids: None,
+            by_folder: Default::default(),
+            quota: None,
+            previews: Vec::new(),
+            delta: 0,
+            last_success: Instant::now(),
+            checked_at: SystemTime::now(),
+            tooltip: None,
+        }
+    }
+
+    /// Expand the format template with the current counts.
+    fn text(&self, format: &str) -> String {
+        let mut values = vec![
+            ("new", self.new_count.to_string()),
+            ("total", self.count.to_string()),
+            ("recent", self.recent.to_string()),
+            ("junk", self.junk.map(|j| j.to_string()).unwrap_or_default()),
+            (
+                "keyword",
+                self.keyword.map(|k| k.to_string()).unwrap_or_default(),
+            ),
+            (
+                "flagged",
+                self.flagged.map(|f| f.to_string()).unwrap_or_default(),
+            ),
+            (
+                "quota",
+                self.quota.map(|q| format!("{}%", q)).unwrap_or_default(),
+            ),
+            ("age", format::age(self.last_success.elapsed())),
+            ("delta", format::delta(self.delta)),
+        ];
+        values.extend(
+            self.watched
+                .iter()
+                .map(|(n, c)| (n.as_str(), c.to_string())),
+        );
+        format::render(format, &values)
+    }
+
+    /// Connection details for the tooltip, telling "no mail" apart from
+    /// "disconnected".
+    pub fn diagnostics(&self, host: &str, folders: &[String], state: &str) -> String {
+        format!(
+            "{}: {}\n{}, last poll {} ago",
+            host,
+            folders.join(", "),
+            state,
+            format::age(self.last_success.elapsed())
+        )
+    }
+}
+
+/// Whether the counts shown are current, or the last known while
+/// reconnecting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connection {
+    Connected,
+    Disconnected,
+}
+
+/// Shown before the text of a disconnected block.
+pub const DISCONNECTED_MARKER: &str = "?";
+
+/// How an account's block is told apart from others, from the config file,
+/// along with the i3bar layout settings given as flags.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Style {
+    /// Account name, set as the i3bar instance for click handlers.
+    pub name: Option<String>,
+    /// Shown before the counts.
+    pub label: Option<String>,
+    /// Shown before the label.
+    pub icon: Option<String>,
+    /// Block color when there are new messages, instead of green.
+    pub color_new: Option<String>,
+    /// Whether i3bar draws a separator after the block.
+    #[serde(skip)]
+    pub separator: Option<bool>,
+    /// Pixels left after the block, for the separator.
+    #[serde(skip)]
+    pub separator_width: Option<u32>,
+    /// Template of the text while disconnected, instead of the format marked
+    /// as stale.
+    #[serde(skip)]
+    pub format_disconnected: Option<String>,
+    /// Template of the i3bar `short_text`, shown when the bar runs out of
+    /// room, instead of the unread count.
+    #[serde(skip)]
+    pub short_format: Option<String>,
+    /// Block color while disconnected, instead of grey.
+    #[serde(skip)]
+    pub color_disconnected: Option<String>,
+    /// Leave out colors and Waybar's `alt` styling hint, even `color_new`.
+    #[serde(skip)]
+    pub no_color: bool,
+    /// Characters the block text is cut to.
+    #[serde(skip)]
+    pub max_width: Option<usize>,
+    /// Adds a `percentage` field to blocks, when set.
+    #[serde(skip)]
+    pub percent: Option<PercentMode>,
+    /// Block colors by unread count, instead of `color_new`.
+    #[serde(skip)]
+    pub color_buckets: Option<ColorBuckets>,
+    /// Quota usage percentage from which the block takes `color_quota`.
+    #[serde(skip)]
+    pub quota_warn: Option<u32>,
+    /// Block color once the quota reaches `quota_warn`, instead of orange.
+    #[serde(skip)]
+    pub color_quota: Option<String>,
+    /// Block color without new messages, instead of the bar's own.
+    #[serde(skip)]
+    pub color_normal: Option<String>,
+    /// New count past which the block takes `color_urgent`.
+    #[serde(skip)]
+    pub urgent_threshold: Option<usize>,
+    /// Block color past `urgent_threshold`, instead of red.
+    #[serde(skip)]
+    pub color_urgent: Option<String>,
+    /// Also urgent while any message counts as flagged.
+    #[serde(skip)]
+    pub urgent_flagged: bool,
+    /// Show the time of the last successful poll, after the i3 text or in
+    /// the Waybar tooltip.
+    #[serde(skip)]
+    pub show_timestamp: bool,
+    /// Show the quota used after the text, when the server tells it.
+    #[serde(skip)]
+    pub show_quota: bool,
+}
+
+impl Style {
+    /// Prefix `text` with the icon and label, when set.
+    fn decorate(&self, text: String) -> String {
+        let mut parts: Vec<&str> = self
+            .icon
+            .iter()
+            .chain(&self.label)
+            .map(|s| s.as_str())
+            .collect();
+        parts.push(&text);
+        parts.join(" ")
+    }
+}
+
+/// One line of i3bar or Waybar JSON; fields left to `None` are omitted.
+#[derive(Default, Serialize)]
+struct Block<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    full_text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urgent: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    markup: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    separator: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    separator_block_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<Class<'a>>,
+    /// Read by Waybar as Pango markup, where senders, subjects and errors
+    /// are plain text.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "escape_markup"
+    )]
+    tooltip: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percentage: Option<u32>,
+}
+
+/// Serialize text for Pango, with `&`, `<` and `>` as entities.
+fn escape_markup<S: serde::Serializer>(text: &Option<&str>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&markup_escaped(text.unwrap_or_default()))
+}
+
+/// `text` with `&`, `<` and `>` as entities, for Pango to show it as is.
+fn markup_escaped(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Waybar CSS classes of a block, which it takes as a string or an array.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Class<'a> {
+    One(&'a str),
+    Many(Vec<&'a str>),
+}
+
+impl OutputMode {
+    /// The block holding `text`, as the mode expects it.
+    fn block<'a>(&self, text: &'a str) -> Block<'a> {
+        match self {
+            OutputMode::I3 => Block {
+                full_text: Some(text),
+                ..Default::default()
+            },
+            OutputMode::Sway => Block {
+                full_text: Some(text),
+                markup: Some("none"),
+                separator: Some(true),
+                ..Default::default()
+            },
+            OutputMode::Waybar | OutputMode::Count | OutputMode::Plain => Block {
+                text: Some(text),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Json block status, with a percentage as set by the style's
+    /// `percent`.
+    ///
+    /// With `compact`, only the unread count is shown, and nothing at all when
+    /// there is none so that the bar hides the block.
+    ///
+    /// While `Disconnected`, the text is marked, or follows the style's
+    /// `format_disconnected`, and the block greyed out.
+    pub fn render(
+        &self,
+        format: &str,
+        compact: bool,
+        style: &Style,
+        status: &Status,
+        conn: Connection,
+    ) -> String {
+        if let OutputMode::Count = self {
+            return status.new_count.to_string();
+        }
+        let flagged = status.new_count > 0;
+        let bucket = style
+            .color_buckets
+            .as_ref()
+            .and_then(|b| b.color(status.new_count));
+        let quota_warn = matches!((status.quota, style.quota_warn), (Some(q), Some(w)) if q >= w);
+        let urgent = style.urgent_threshold.is_some_and(|t| status.new_count > t)
+            || (style.urgent_flagged && status.flagged.is_some_and(|f| f > 0));
+        let disconnected = conn == Connection::Disconnected;
+        let own_format = style
+            .format_disconnected
+            .as_deref()
+            .filter(|_| disconnected);
+        let text = match (own_format, compact, flagged) {
+            (Some(format), _, _) => status.text(format),
+            (None, false, _) => status.text(format),
+            (None, true, true) => status.new_count.to_string(),
+            (None, true, false) => String::new(),
+        };
+        let text = match status
+            .quota
+            .filter(|_| style.show_quota && !text.is_empty())
+        {
+            Some(q) => format!("{} {}% full", text, q),
+            None => text,
+        };
+        let text = match (disconnected && own_format.is_none(), text.is_empty()) {
+            (true, false) => style.decorate(format!("{} {}", DISCONNECTED_MARKER, text)),
+            (true, true) => style.decorate(DISCONNECTED_MARKER.into()),
+            (false, false) => style.decorate(text),
+            (false, true) => text,
+        };
+        let checked = style
+            .show_timestamp
+            .then(|| format::clock(status.checked_at));
+        // Waybar has it in the tooltip instead.
+        let text = match (&checked, self) {
+            (Some(at), OutputMode::I3 | OutputMode::Sway) if !text.is_empty() => {
+                format!("{} {}", text, at)
+            }
+            _ => text,
+        };
+        // Clock included, the width is all the bar has room for.
+        let text = match style.max_width {
+            Some(max) => format::truncate(text, max),
+            None => text,
+        };
+        if let OutputMode::Plain = self {
+            return text;
+        }
+        // Hidden, bars collapse the block only without color nor class.
+        if text.is_empty() {
+            return serde_json::to_string(&self.block(&text)).unwrap();
+        }
+        let short = match &style.short_format {
+            Some(format) => status.text(format),
+            None => status.new_count.to_string(),
+        };
+        let short = match disconnected && own_format.is_none() {
+            true => format!("{} {}", DISCONNECTED_MARKER, short),
+            false => short,
+        };
+        let tooltip = match (&checked, &status.tooltip) {
+            (Some(at), Some(tip)) => Some(format!("{}\n\nLast checked: {}", tip, at)),
+            (Some(at), None) => Some(format!("Last checked: {}", at)),
+            (None, tip) => tip.clone(),
+        };
+        let mut block = self.block(&text);
+        block.percentage = style.percent.map(|p| p.percent(status));
+        if let OutputMode::I3 = self {
+            block.short_text = Some(&short);
+        }
+        match self {
+            OutputMode::I3 | OutputMode::Sway => {
+                block.instance = style.name.as_deref();
+                block.separator = style.separator.or(block.separator);
+                block.separator_block_width = style.separator_width;
+                block.color = match (style.no_color, disconnected, flagged) {
+                    (true, _, _) => None,
+                    (false, true, _) => {
+                        Some(style.color_disconnected.as_deref().unwrap_or("#808080"))
+                    }
+                    (false, false, _) if quota_warn => {
+                        Some(style.color_quota.as_deref().unwrap_or("#ff8800"))
+                    }
+                    (false, false, _) if status.color.is_some() => status.color.as_deref(),
+                    (false, false, _) if urgent => {
+                        Some(style.color_urgent.as_deref().unwrap_or("#ff0000"))
+                    }
+                    (false, false, true) if bucket.is_some() => bucket,
+                    (false, false, true) => Some(style.color_new.as_deref().unwrap_or("#00cc00")),
+                    (false, false, false) => Some(style.color_normal.as_deref().unwrap_or("")),
+                };
+            }
+            OutputMode::Waybar => {
+                block.alt = (!style.no_color).then(|| match disconnected {
+                    true => "disconnected".into(),
+                    false => flagged.to_string(),
+                });
+                // Kept even without colors, styling is then up to the CSS.
+                let mut class = vec![match (disconnected, flagged) {
+                    (true, _) => "disconnected",
+                    (false, true) => "new",
+                    (false, false) => "none",
+                }];
+                // For stylesheets written against other mail blocks.
+                if !disconnected {
+                    class.push(if flagged { "unread" } else { "read" });
+                }
+                if quota_warn && !disconnected {
+                    class.push("warn");
+                }
+                if urgent && !disconnected {
+                    class.push("urgent");
+                }
+                block.class = Some(Class::Many(class));
+                block.tooltip = tooltip.as_deref();
+            }
+            OutputMode::Count | OutputMode::Plain => unreachable!(),
+        }
+        serde_json::to_string(&block).unwrap()
+    }
+
+    /// Json block for problems retrying won't fix.
+    ///
+    /// Count consumers expect a number, so nothing is shown there and the
+    /// error is left to the log. Plain shows the message as is.
+    pub fn render_error(&self, msg: &str) -> Option<String> {
+        // Waybar reads text as markup, which an error message never is.
+        let escaped = markup_escaped(msg);
+        let mut block = match self {
+            OutputMode::Waybar => self.block(&escaped),
+            _ => self.block(msg),
+        };
+        match self {
+            OutputMode::I3 | OutputMode::Sway => block.urgent = Some(true),
+            OutputMode::Waybar => block.class = Some(Class::One("error")),
+            OutputMode::Count => return None,
+            OutputMode::Plain => return Some(msg.into()),
+        }
+        Some(serde_json::to_string(&block).unwrap())
+    }
+
+    /// Json block while the server was never reached, without counts to
+    /// show as stale: the disconnected marker, or `format_disconnected` when
+    /// it has no tokens to fill. Count keeps showing nothing.
+    pub fn render_unreached(&self, style: &Style) -> Option<String> {
+        let text = match &style.format_disconnected {
+            Some(format) if format::placeholders(format).is_empty() => format.clone(),
+            _ => style.decorate(DISCONNECTED_MARKER.into()),
+        };
+        let mut block = self.block(&text);
+        match self {
+            OutputMode::I3 | OutputMode::Sway => {
+                if let OutputMode::I3 = self {
+                    block.short_text = Some(DISCONNECTED_MARKER);
+                }
+                block.instance = style.name.as_deref();
+                block.separator = style.separator.or(block.separator);
+                block.separator_block_width = style.separator_width;
+                block.color = (!style.no_color)
+                    .then(|| style.color_disconnected.as_deref().unwrap_or("#808080"));
+            }
+            OutputMode::Waybar => {
+                block.alt = (!style.no_color).then(|| "disconnected".into());
+                block.class = Some(Class::Many(vec!["disconnected"]));
+            }
+            OutputMode::Count => return None,
+            OutputMode::Plain => return Some(text),
+        }
+        Some(serde_json::to_string(&block).unwrap())
+    }
+
+    /// Json block shown while snoozed, for `left` more. Count keeps its last
+    /// number.
+    pub fn render_snoozed(&self, left: Duration) -> Option<String> {
+        let text = format!("snoozed {}", format::age(left));
+        let mut block = self.block(&text);
+        match self {
+            OutputMode::I3 | OutputMode::Sway => (),
+            OutputMode::Waybar => {
+                block.alt = Some("snoozed".into());
+                block.class = Some(Class::One("snoozed"));
+            }
+            OutputMode::Count => return None,
+            OutputMode::Plain => return Some(text),
+        }
+        Some(serde_json::to_string(&block).unwrap())
+    }
+
+    /// Write json block status to stdout, or the `--output` path.
+    pub fn dump_status(
+        &self,
+        format: &str,
+        compact: bool,
+        style: &Style,
+        status: &Status,
+        conn: Connection,
+    ) {
+        emit(&self.render(format, compact, style, status, conn));
+    }
+
+    /// Write the snoozed block to stdout.
+    pub fn dump_snoozed(&self, left: Duration) {
+        if let Some(line) = self.render_snoozed(left) {
+            emit(&line);
+        }
+    }
+
+    /// Write the block of a server not reached yet to stdout.
+    pub fn dump_unreached(&self, style: &Style) {
+        if let Some(line) = self.render_unreached(style) {
+            emit(&line);
+        }
+    }
+
+    /// Write an error block to stdout.
+    pub fn dump_error(&self, msg: &str) {
+        if let Some(line) = self.render_error(msg) {
+            emit(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Connection::{Connected, Disconnected};
+    use super::{ColorBuckets, OutputMode, PercentMode, Status, Style};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::time::Duration;
+
+    fn status(new_count: usize, count: u32) -> Status {
+        Status::new(new_count, count)
+    }
+
+    #[test]
+    fn test_render() {
+        let s = status(2, 42);
+        assert_eq!(
+            r##"{"full_text":"(2) 42","short_text":"2","color":"#00cc00"}"##,
+            OutputMode::I3.render("({new}) {total}", false, &Style::default(), &s, Connected)
+        );
+        assert_eq!(
+            r#"{"text":"(2) 42","alt":"true","class":["new","unread"]}"#,
+            OutputMode::Waybar.render("({new}) {total}", false, &Style::default(), &s, Connected)
+        );
+        assert_eq!(
+            r#"{"full_text":""}"#,
+            OutputMode::I3.render(
+                "({new}) {total}",
+                true,
+                &Style::default(),
+                &status(0, 42),
+                Connected
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_counts() {
+        let render = |mode: OutputMode, new, total| {
+            let s = status(new, total);
+            mode.render("({new}) {total}", false, &Style::default(), &s, Connected)
+        };
+        for (mode, new, total, want) in [
+            (
+                OutputMode::I3,
+                0,
+                0,
+                r#"{"full_text":"(0) 0","short_text":"0","color":""}"#,
+            ),
+            (
+                OutputMode::I3,
+                7,
+                250_000,
+                r##"{"full_text":"(7) 250000","short_text":"7","color":"#00cc00"}"##,
+            ),
+            (
+                OutputMode::Sway,
+                0,
+                42,
+                r#"{"full_text":"(0) 42","color":"","markup":"none","separator":true}"#,
+            ),
+            (
+                OutputMode::Waybar,
+                0,
+                42,
+                r#"{"text":"(0) 42","alt":"false","class":["none","read"]}"#,
+            ),
+            (
+                OutputMode::Waybar,
+                12_345,
+                4_000_000_000,
+                r#"{"text":"(12345) 4000000000","alt":"true","class":["new","unread"]}"#,
+            ),
+        ] {
+            assert_eq!(want, render(mode, new, total), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_render_watched() {
+        let mut s = status(3, 42);
+        s.watched = vec![("drafts".into(), 2)];
+        assert_eq!(
+            r##"{"full_text":"(3) drafts 2","short_text":"3","color":"#00cc00"}"##,
+            OutputMode::I3.render(
+                "({new}) drafts {drafts}",
+                false,
+                &Style::default(),
+                &s,
+                Connected
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_max_width() {
+        let style = Style {
+            label: Some("work".into()),
+            max_width: Some(8),
+            ..Default::default()
+        };
+        assert_eq!(
+            r#"{"text":"work (2…","alt":"true","class":["new","unread"]}"#,
+            OutputMode::Waybar.render("({new}) {total}", false, &style, &status(2, 42), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_no_color() {
+        let style = Style {
+            color_new: Some("#ff0000".into()),
+            no_color: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            r#"{"full_text":"(2) 42","short_text":"2"}"#,
+            OutputMode::I3.render("({new}) {total}", false, &style, &status(2, 42), Connected)
+        );
+        assert_eq!(
+            r#"{"text":"(2) 42","class":["new","unread"]}"#,
+            OutputMode::Waybar.render("({new}) {total}", false, &style, &status(2, 42), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_urgent() {
+        let style = Style {
+            color_normal: Some("#ffffff".into()),
+            urgent_threshold: Some(10),
+            color_urgent: Some("#ff00ff".into()),
+            ..Default::default()
+        };
+        let render = |mode: OutputMode, new| {
+            mode.render("{new}", false, &style, &status(new, 42), Connected)
+        };
+        assert_eq!(
+            r##"{"full_text":"0","short_text":"0","color":"#ffffff"}"##,
+            render(OutputMode::I3, 0)
+        );
+        assert_eq!(
+            r##"{"full_text":"10","short_text":"10","color":"#00cc00"}"##,
+            render(OutputMode::I3, 10)
+        );
+        assert_eq!(
+            r##"{"full_text":"11","short_text":"11","color":"#ff00ff"}"##,
+            render(OutputMode::I3, 11)
+        );
+        assert_eq!(
+            r#"{"text":"11","alt":"true","class":["new","unread","urgent"]}"#,
+            render(OutputMode::Waybar, 11)
+        );
+    }
+
+    #[test]
+    fn test_render_urgent_flagged() {
+        let style = Style {
+            urgent_flagged: true,
+            ..Default::default()
+        };
+        let render = |mode: OutputMode, flagged| {
+            let status = Status {
+                flagged: Some(flagged),
+                ..status(0, 42)
+            };
+            mode.render("{new}/{flagged}", false, &style, &status, Connected)
+        };
+        assert_eq!(
+            r#"{"text":"0/0","alt":"false","class":["none","read"]}"#,
+            render(OutputMode::Waybar, 0)
+        );
+        assert_eq!(
+            r#"{"text":"0/1","alt":"false","class":["none","read","urgent"]}"#,
+            render(OutputMode::Waybar, 1)
+        );
+        assert_eq!(
+            r##"{"full_text":"0/1","short_text":"0","color":"#ff0000"}"##,
+            render(OutputMode::I3, 1)
+        );
+    }
+
+    #[test]
+    fn test_render_unreached() {
+        let mut style = Style {
+            label: Some("work".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(r##"{"full_text":"work ?","short_text":"?","color":"#808080"}"##.into()),
+            OutputMode::I3.render_unreached(&style)
+        );
+        assert_eq!(
+            Some(r#"{"text":"work ?","alt":"disconnected","class":["disconnected"]}"#.into()),
+            OutputMode::Waybar.render_unreached(&style)
+        );
+        assert_eq!(None, OutputMode::Count.render_unreached(&style));
+
+        style.format_disconnected = Some("⚠".into());
+        assert_eq!(Some("⚠".into()), OutputMode::Plain.render_unreached(&style));
+        // Counts there are none of yet.
+        style.format_disconnected = Some("⚠ {new}".into());
+        assert_eq!(
+            Some("work ?".into()),
+            OutputMode::Plain.render_unreached(&style)
+        );
+    }
+
+    #[test]
+    fn test_render_timestamp() {
+        let style = Style {
+            show_timestamp: true,
+            no_color: true,
+            ..Default::default()
+        };
+        let mut s = status(2, 42);
+        s.checked_at = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = crate::format::clock(s.checked_at);
+        assert_eq!(
+            format!(r#"{{"full_text":"(2) 42 {}","short_text":"2"}}"#, at),
+            OutputMode::I3.render("({new}) {total}", false, &style, &s, Connected)
+        );
+        // Within --max-width along with the text.
+        let narrow = Style {
+            max_width: Some(8),
+            ..style.clone()
+        };
+        let cut = crate::format::truncate(format!("(2) 42 {}", at), 8);
+        assert_eq!(8, cut.chars().count());
+        assert_eq!(
+            format!(r#"{{"full_text":"{}","short_text":"2"}}"#, cut),
+            OutputMode::I3.render("({new}) {total}", false, &narrow, &s, Connected)
+        );
+        s.tooltip = Some("INBOX: 2".into());
+        assert_eq!(
+            format!(
+                r#"{{"text":"(2) 42","class":["new","unread"],"tooltip":"INBOX: 2\n\nLast checked: {}"}}"#,
+                at
+            ),
+            OutputMode::Waybar.render("({new}) {total}", false, &style, &s, Connected)
+        );
+        // Nothing to show it after in a hidden compact block.
+        assert_eq!(
+            r#"{"full_text":""}"#,
+            OutputMode::I3.render("{new}", true, &style, &status(0, 42), Connected)
+        );
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status");
+        super::write_to(&path, "first").unwrap();
+        super::write_to(&path, "second").unwrap();
+        assert_eq!("second\n", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn test_write_badge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("badge");
+        super::write_badge(&path, 3);
+        super::write_badge(&path, 0);
+        assert_eq!("0\n", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn test_write_status_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.json");
+        super::write_status_file(&path, Some("imap.example.com"), Disconnected, None);
+        assert_eq!(
+            r#"{"connected":false,"host":"imap.example.com","checked_at":null,"unseen":null,"total":null}"#,
+            std::fs::read_to_string(&path).unwrap().trim_end()
+        );
+        let mut s = status(3, 42);
+        s.checked_at = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        super::write_status_file(&path, None, Connected, Some(&s));
+        assert_eq!(
+            r#"{"connected":true,"checked_at":1700000000,"unseen":3,"total":42}"#,
+            std::fs::read_to_string(&path).unwrap().trim_end()
+        );
+    }
+
+    #[test]
+    fn test_write_to_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status");
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) });
+        // No reader, dropped at once.
+        super::write_to(&path, "unread").unwrap_err();
+
+        let reader = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        super::write_to(&path, "first").unwrap();
+        super::write_to(&path, "second").unwrap();
+        let mut buf = [0; 64];
+        let n = std::io::Read::read(&mut &reader, &mut buf).unwrap();
+        assert_eq!(b"first\nsecond\n", &buf[..n]);
+    }
+
+    #[test]
+    fn test_render_percent() {
+        let style = Style {
+            percent: Some(PercentMode::Ratio),
+            ..Default::default()
+        };
+        assert_eq!(
+            r#"{"text":"(2) 8","alt":"true","class":["new","unread"],"percentage":25}"#,
+            OutputMode::Waybar.render("({new}) {total}", false, &style, &status(2, 8), Connected)
+        );
+        assert_eq!(
+            r##"{"full_text":"(0) 0","short_text":"0","color":"","percentage":0}"##,
+            OutputMode::I3.render("({new}) {total}", false, &style, &status(0, 0), Connected)
+        );
+        let style = Style {
+            percent: Some(PercentMode::Any),
+            ..Default::default()
+        };
+        assert_eq!(
+            r#"{"text":"(2) 8","alt":"true","class":["new","unread"],"percentage":100}"#,
+            OutputMode::Waybar.render("({new}) {total}", false, &style, &status(2, 8), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_disconnected() {
+        let s = status(2, 42);
+        assert_eq!(
+            r##"{"full_text":"? (2) 42","short_text":"? 2","color":"#808080"}"##,
+            OutputMode::I3.render(
+                "({new}) {total}",
+                false,
+                &Style::default(),
+                &s,
+                Disconnected
+            )
+        );
+        let style = Style {
+            color_disconnected: Some("#ff8800".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"?","short_text":"? 0","color":"#ff8800"}"##,
+            OutputMode::I3.render(
+                "({new}) {total}",
+                true,
+                &style,
+                &status(0, 42),
+                Disconnected
+            )
+        );
+        assert_eq!(
+            r#"{"text":"? (2) 42","alt":"disconnected","class":["disconnected"]}"#,
+            OutputMode::Waybar.render("({new}) {total}", false, &style, &s, Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_render_short_format() {
+        let style = Style {
+            short_format: Some("✉{new}".into()),
+            ..Default::default()
+        };
+        let s = status(2, 42);
+        assert_eq!(
+            r##"{"full_text":"(2) 42","short_text":"✉2","color":"#00cc00"}"##,
+            OutputMode::I3.render("({new}) {total}", false, &style, &s, Connected)
+        );
+        // Other modes have no room for it.
+        assert_eq!(
+            r##"{"full_text":"(2) 42","color":"#00cc00","markup":"none","separator":true}"##,
+            OutputMode::Sway.render("({new}) {total}", false, &style, &s, Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_format_disconnected() {
+        let style = Style {
+            label: Some("work".into()),
+            format_disconnected: Some("⚠".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"work ⚠","short_text":"2","color":"#808080"}"##,
+            OutputMode::I3.render(
+                "({new}) {total}",
+                true,
+                &style,
+                &status(2, 42),
+                Disconnected
+            )
+        );
+        assert_eq!(
+            r##"{"full_text":"work (2) 42","short_text":"2","color":"#00cc00"}"##,
+            OutputMode::I3.render("({new}) {total}", false, &style, &status(2, 42), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_rule_color() {
+        let mut s = status(2, 42);
+        s.color = Some("#ff0000".into());
+        let style = Style {
+            color_new: Some("#0000ff".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"(2) 42","short_text":"2","color":"#ff0000"}"##,
+            OutputMode::I3.render("({new}) {total}", false, &style, &s, Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_quota() {
+        let mut s = status(0, 42);
+        assert_eq!(
+            r#"{"full_text":"(0) ","short_text":"0","color":""}"#,
+            OutputMode::I3.render("({new}) {quota}", false, &Style::default(), &s, Connected)
+        );
+        s.quota = Some(91);
+        let style = Style {
+            quota_warn: Some(90),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"(0) 91%","short_text":"0","color":"#ff8800"}"##,
+            OutputMode::I3.render("({new}) {quota}", false, &style, &s, Connected)
+        );
+        s.quota = Some(85);
+        assert_eq!(
+            r#"{"full_text":"(0) 85%","short_text":"0","color":""}"#,
+            OutputMode::I3.render("({new}) {quota}", false, &style, &s, Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_show_quota() {
+        let style = Style {
+            show_quota: true,
+            ..Default::default()
+        };
+        let mut s = status(2, 42);
+        // Not told by the server.
+        assert_eq!(
+            "(2) 42",
+            OutputMode::Plain.render("({new}) {total}", false, &style, &s, Connected)
+        );
+        s.quota = Some(80);
+        assert_eq!(
+            "(2) 42 80% full",
+            OutputMode::Plain.render("({new}) {total}", false, &style, &s, Connected)
+        );
+        s.new_count = 0;
+        assert_eq!(
+            "",
+            OutputMode::Plain.render("{new}", true, &style, &s, Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_waybar_class() {
+        let style = Style {
+            quota_warn: Some(90),
+            ..Default::default()
+        };
+        let mut s = status(0, 42);
+        assert_eq!(
+            r#"{"text":"0","alt":"false","class":["none","read"]}"#,
+            OutputMode::Waybar.render("{new}", false, &style, &s, Connected)
+        );
+        s.new_count = 1;
+        s.quota = Some(95);
+        assert_eq!(
+            r#"{"text":"1","alt":"true","class":["new","unread","warn"]}"#,
+            OutputMode::Waybar.render("{new}", false, &style, &s, Connected)
+        );
+        assert_eq!(
+            r#"{"text":"? 1","alt":"disconnected","class":["disconnected"]}"#,
+            OutputMode::Waybar.render("{new}", false, &style, &s, Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_color_buckets() {
+        let buckets: ColorBuckets = "5:#00cc00,20:#cccc00,:#cc0000".parse().unwrap();
+        assert_eq!(Some("#00cc00"), buckets.color(1));
+        assert_eq!(Some("#cccc00"), buckets.color(6));
+        assert_eq!(Some("#cc0000"), buckets.color(21));
+        let closed: ColorBuckets = "5:#00cc00".parse().unwrap();
+        assert_eq!(None, closed.color(6));
+
+        assert!("5".parse::<ColorBuckets>().is_err());
+        assert!("5:".parse::<ColorBuckets>().is_err());
+        assert!("x:#00cc00".parse::<ColorBuckets>().is_err());
+        assert!(":#00cc00,5:#cc0000".parse::<ColorBuckets>().is_err());
+        assert!("20:#00cc00,5:#cc0000".parse::<ColorBuckets>().is_err());
+
+        let style = Style {
+            color_new: Some("#0000ff".into()),
+            color_buckets: Some(closed),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"3","short_text":"3","color":"#00cc00"}"##,
+            OutputMode::I3.render("{new}", false, &style, &status(3, 42), Connected)
+        );
+        assert_eq!(
+            r##"{"full_text":"6","short_text":"6","color":"#0000ff"}"##,
+            OutputMode::I3.render("{new}", false, &style, &status(6, 42), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_delta() {
+        let mut s = status(3, 42);
+        s.delta = 1;
+        assert_eq!(
+            r##"{"full_text":"(3 +1)","short_text":"3","color":"#00cc00"}"##,
+            OutputMode::I3.render("({new} {delta})", false, &Style::default(), &s, Connected)
+        );
+        s.delta = -2;
+        assert_eq!(
+            r##"{"full_text":"(3 -2)","short_text":"3","color":"#00cc00"}"##,
+            OutputMode::I3.render("({new} {delta})", false, &Style::default(), &s, Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_style() {
+        let style = Style {
+            name: Some("work".into()),
+            label: Some("Work".into()),
+            icon: Some("✉".into()),
+            color_new: Some("#ff8800".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"✉ Work (2) 42","short_text":"2","instance":"work","color":"#ff8800"}"##,
+            OutputMode::I3.render("({new}) {total}", false, &style, &status(2, 42), Connected)
+        );
+        assert_eq!(
+            r#"{"full_text":"✉ Work 0","short_text":"0","instance":"work","color":""}"#,
+            OutputMode::I3.render("{new}", false, &style, &status(0, 42), Connected)
+        );
+        assert_eq!(
+            r#"{"text":"✉ Work 2","alt":"true","class":["new","unread"]}"#,
+            OutputMode::Waybar.render("{new}", true, &style, &status(2, 42), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_hidden() {
+        let style = Style {
+            color_normal: Some("#ffffff".into()),
+            percent: Some(PercentMode::Ratio),
+            ..Default::default()
+        };
+        let render = |mode: OutputMode, new| {
+            mode.render("({new}) {total}", true, &style, &status(new, 42), Connected)
+        };
+        assert_eq!(r#"{"full_text":""}"#, render(OutputMode::I3, 0));
+        assert_eq!(
+            r#"{"full_text":"","markup":"none","separator":true}"#,
+            render(OutputMode::Sway, 0)
+        );
+        assert_eq!(r#"{"text":""}"#, render(OutputMode::Waybar, 0));
+        assert_eq!(
+            r#"{"text":"3","alt":"true","class":["new","unread"],"percentage":7}"#,
+            render(OutputMode::Waybar, 3)
+        );
+    }
+
+    #[test]
+    fn test_render_separator() {
+        let style = Style {
+            separator: Some(false),
+            separator_width: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            r##"{"full_text":"1","color":"#00cc00","markup":"none","separator":false,"separator_block_width":0}"##,
+            OutputMode::Sway.render("{new}", false, &style, &status(1, 1), Connected)
+        );
+        assert_eq!(
+            r#"{"text":"1","alt":"true","class":["new","unread"]}"#,
+            OutputMode::Waybar.render("{new}", false, &style, &status(1, 1), Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_tooltip() {
+        let mut s = status(1, 1);
+        s.tooltip = Some(s.diagnostics("imap.example.com", &["INBOX".into()], "idling"));
+        assert_eq!(
+            r#"{"text":"1","alt":"true","class":["new","unread"],"tooltip":"imap.example.com: INBOX\nidling, last poll 0s ago"}"#,
+            OutputMode::Waybar.render("{new}", false, &Style::default(), &s, Connected)
+        );
+        // No such thing in i3bar.
+        assert_eq!(
+            r##"{"full_text":"1","short_text":"1","color":"#00cc00"}"##,
+            OutputMode::I3.render("{new}", false, &Style::default(), &s, Connected)
+        );
+    }
+
+    #[test]
+    fn test_render_snoozed() {
+        let left = Duration::from_secs(1800);
+        assert_eq!(
+            r#"{"full_text":"snoozed 30m"}"#,
+            OutputMode::I3.render_snoozed(left).unwrap()
+        );
+        assert_eq!(
+            r#"{"text":"snoozed 30m","alt":"snoozed","class":"snoozed"}"#,
+            OutputMode::Waybar.render_snoozed(left).unwrap()
+        );
+        assert_eq!(None, OutputMode::Count.render_snoozed(left));
+        assert_eq!(
+            Some("snoozed 30m".into()),
+            OutputMode::Plain.render_snoozed(left)
+        );
+    }
+
+    #[test]
+    fn test_render_escaped() {
+        let s = status(1, 1);
+        for mode in [OutputMode::I3, OutputMode::Sway, OutputMode::Waybar] {
+            let line = mode.render(
+                r#"say "hi" \ {new}"#,
+                false,
+                &Style::default(),
+                &s,
+                Connected,
+            );
+            let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+            let text = v.get("full_text").or(v.get("text")).unwrap();
+            assert_eq!(r#"say "hi" \ 1"#, text);
+        }
+
+        let mut s = status(1, 1);
+        s.tooltip = Some(r#"Bob <bob@example.com>: "Q&A" \ notes"#.into());
+        let line = OutputMode::Waybar.render("{new}", false, &Style::default(), &s, Connected);
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            r#"Bob &lt;bob@example.com&gt;: "Q&amp;A" \ notes"#,
+            v["tooltip"]
+        );
+    }
+
+    #[test]
+    fn test_render_error() {
+        assert_eq!(
+            r#"{"text":"no \"x\"","class":"error"}"#,
+            OutputMode::Waybar.render_error("no \"x\"").unwrap()
+        );
+        assert_eq!(None, OutputMode::Count.render_error("no \"x\""));
+        // Waybar takes text as markup, i3 doesn't unless asked.
+        assert_eq!(
+            r#"{"text":"no mailbox Q&amp;A &lt;x&gt;","class":"error"}"#,
+            OutputMode::Waybar
+                .render_error("no mailbox Q&A <x>")
+                .unwrap()
+        );
+        assert_eq!(
+            r#"{"full_text":"no mailbox Q&A","urgent":true}"#,
+            OutputMode::I3.render_error("no mailbox Q&A").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_format() {
+        assert!(super::check_format("({new}) {flagged}", &[]).is_ok());
+        assert!(super::check_format("{lists}", &["lists"]).is_ok());
+        assert_eq!(
+            Err("unknown token {nwe} in \"({nwe}) {total}\"".into()),
+            super::check_format("({nwe}) {total}", &[])
+        );
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let style = Style {
+            color_new: Some("#ff0000".into()),
+            ..Default::default()
+        };
+        let s = status(3, 42);
+        assert_eq!(
+            "(3) 42",
+            OutputMode::Plain.render("({new}) {total}", false, &style, &s, Connected)
+        );
+        assert_eq!(
+            format!("{} (3) 42", super::DISCONNECTED_MARKER),
+            OutputMode::Plain.render("({new}) {total}", false, &style, &s, Disconnected)
+        );
+        assert_eq!(
+            Some("no \"x\"".into()),
+            OutputMode::Plain.render_error("no \"x\"")
+        );
+    }
+
+    #[test]
+    fn test_render_count() {
+        assert_eq!(
+            "3",
+            OutputMode::Count.render(
+                "({new}) {total}",
+                false,
+                &Style::default(),
+                &status(3, 42),
+                Connected
+            )
+        );
+        assert_eq!(
+            "0",
+            OutputMode::Count.render(
+                "({new}) {total}",
+                true,
+                &Style::default(),
+                &status(0, 42),
+                Connected
+            )
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_std::io::{Read, Write};
+
+/// What the server greeting said, once read.
+#[derive(Debug)]
+enum Greeting {
+    /// Its start so far, for a fresh connection.
+    Pending(Vec<u8>),
+    /// Already authenticated by the transport, the next LOGIN or
+    /// AUTHENTICATE is answered here.
+    Preauth,
+    /// Anything else, or not a fresh connection.
+    Normal,
+}
+
+/// How far the login went, for the capabilities sent along its reply.
+#[derive(Debug, PartialEq)]
+enum Login {
+    /// Still to be sent.
+    Waiting,
+    /// Sent with this tag, its reply still unread.
+    Reply(Vec<u8>),
+    /// Done, with the capabilities of its reply code if any, to answer the
+    /// next CAPABILITY with.
+    Caps(Option<Vec<u8>>),
+    /// Nothing more to look at.
+    Done,
+}
+
+/// A stream standing in for the server around the login.
+///
+/// When the server greets with PREAUTH, as local relays do behind stunnel or
+/// a unix socket, the LOGIN or AUTHENTICATE is answered here: the server
+/// would reject it in that state, and async-imap has no session without one.
+///
+/// When the login reply carries a `[CAPABILITY ...]` code, as most servers
+/// send, the CAPABILITY query right after is answered from it, saving a
+/// round-trip. async-imap doesn't surface tagged response codes itself.
+#[derive(Debug)]
+pub struct Preauth<T> {
+    inner: T,
+    greeting: Greeting,
+    login: Login,
+    /// Read ahead of the client, or made up, returned before reading on.
+    replay: Vec<u8>,
+    /// A command being written while the login is still to be looked at.
+    line: Vec<u8>,
+    /// A server line being read while waiting for the login reply.
+    reply: Vec<u8>,
+    /// Commands on their way to the server, flushed first.
+    out: Vec<u8>,
+}
+
+impl<T> Preauth<T> {
+    /// Wrap a stream whose greeting is still unread when `fresh`, rather than
+    /// one upgraded with STARTTLS.
+    pub fn new(inner: T, fresh: bool) -> Preauth<T> {
+        Preauth {
+            inner,
+            greeting: match fresh {
+                true => Greeting::Pending(Vec::new()),
+                false => Greeting::Normal,
+            },
+            login: Login::Waiting,
+            replay: Vec::new(),
+            line: Vec::new(),
+            reply: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// Whether commands go through as they are written.
+    fn passthrough(&self) -> bool {
+        matches!(self.greeting, Greeting::Normal) && self.login == Login::Done
+    }
+
+    /// Look at what the server sent, for the greeting and the login reply.
+    fn sniff(&mut self, mut data: &[u8]) {
+        if let Greeting::Pending(start) = &mut self.greeting {
+            let end = data.iter().position(|&b| b == b'\n');
+            start.extend_from_slice(&data[..end.unwrap_or(data.len())]);
+            let Some(end) = end else {
+                return;
+            };
+            let preauth = start
+                .get(..9)
+                .is_some_and(|s| s.eq_ignore_ascii_case(b"* PREAUTH"));
+            self.greeting = match preauth {
+                true => {
+                    log::debug!("server greeted with PREAUTH, skipping login");
+                    Greeting::Preauth
+                }
+                false => Greeting::Normal,
+            };
+            data = &data[end + 1..];
+        }
+        while let Login::Reply(tag) = &self.login {
+            let end = data.iter().position(|&b| b == b'\n');
+            self.reply
+                .extend_from_slice(&data[..end.map_or(data.len(), |e| e + 1)]);
+            let Some(end) = end else {
+                return;
+            };
+            data = &data[end + 1..];
+            let line = std::mem::take(&mut self.reply);
+            let Some(rest) = line.strip_prefix(tag.as_slice()) else {
+                continue;
+            };
+            self.login = match rest.get(..4) {
+                Some(ok) if ok.eq_ignore_ascii_case(b" OK ") => Login::Caps(caps_code(&rest[4..])),
+                _ => Login::Done,
+            };
+        }
+    }
+
+    /// Pass a complete command on, unless it is answered here.
+    fn command(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        if let Login::Reply(_) = self.login {
+            // AUTHENTICATE exchanges, not commands.
+            self.out.extend_from_slice(&line);
+            return;
+        }
+        let mut words = line.split(|&b| b == b' ' || b == b'\r');
+        let (tag, cmd) = (words.next().unwrap_or_default(), words.next());
+        let is = |name: &[u8]| cmd.is_some_and(|c| c.eq_ignore_ascii_case(name));
+        let login = is(b"LOGIN") || is(b"AUTHENTICATE");
+        match (&self.login, &self.greeting) {
+            (Login::Waiting, Greeting::Preauth) if login => {
+                self.answer(tag, None);
+                self.greeting = Greeting::Normal;
+                self.login = Login::Done;
+            }
+            (Login::Waiting, _) if login => {
+                self.login = Login::Reply(tag.to_vec());
+                self.out.extend_from_slice(&line);
+            }
+            (Login::Caps(Some(caps)), _) if is(b"CAPABILITY") => {
+                let caps = caps.clone();
+                self.answer(tag, Some(&caps));
+                self.login = Login::Done;
+            }
+            (Login::Caps(_), _) => {
+                self.login = Login::Done;
+                self.out.extend_from_slice(&line);
+            }
+            _ => self.out.extend_from_slice(&line),
+        }
+    }
+
+    /// Reply to the command tagged `tag` in the server's stead, with the
+    /// capabilities to list if it is a CAPABILITY.
+    fn answer(&mut self, tag: &[u8], caps: Option<&[u8]>) {
+        if let Some(caps) = caps {
+            self.replay.extend_from_slice(b"* CAPABILITY ");
+            self.replay.extend_from_slice(caps);
+            self.replay.extend_from_slice(b"\r\n");
+        }
+        self.replay.extend_from_slice(tag);
+        self.replay.extend_from_slice(match caps {
+            Some(_) => b" OK CAPABILITY from login\r\n",
+            None => b" OK PREAUTH\r\n",
+        });
+    }
+}
+
+/// The capability names of a `[CAPABILITY ...]` response code at the start
+/// of `text`.
+fn caps_code(text: &[u8]) -> Option<Vec<u8>> {
+    let prefix = b"[CAPABILITY ";
+    let start = text.get(..prefix.len())?;
+    if !start.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = &text[prefix.len()..];
+    let end = rest.iter().position(|&b| b == b']')?;
+    Some(rest[..end].to_vec())
+}
+
+impl<T: Read + Unpin> Preauth<T> {
+    /// Read the greeting ahead, to know whether commands go through.
+    fn poll_greeting(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Greeting::Pending(_) = self.greeting {
+            let mut buf = [0; 1024];
+            let n = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.replay.extend_from_slice(&buf[..n]);
+            self.sniff(&buf[..n]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Write + Unpin> Preauth<T> {
+    fn poll_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Read + Unpin> Read for Preauth<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.replay.is_empty() {
+            let n = buf.len().min(this.replay.len());
+            buf[..n].copy_from_slice(&this.replay[..n]);
+            this.replay.drain(..n);
+            return Poll::Ready(Ok(n));
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.sniff(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: Read + Write + Unpin> Write for Preauth<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_greeting(cx))?;
+        ready!(this.poll_out(cx))?;
+        if this.passthrough() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                this.line.extend_from_slice(&buf[..=end]);
+                this.command();
+                Poll::Ready(Ok(end + 1))
+            }
+            None => {
+                this.line.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_out(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_std::io::Cursor;
+    use async_std::prelude::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_preauth() {
+        block_on(async {
+            let server = Cursor::new(b"* PREAUTH welcome\r\n".to_vec());
+            let mut s = super::Preauth::new(server, true);
+            s.write_all(b"A1 LOGIN \"me\" \"secret\"\r\n")
+                .await
+                .unwrap();
+            s.write_all(b"A2 NOOP\r\n").await.unwrap();
+            s.flush().await.unwrap();
+            let mut got = String::new();
+            s.read_to_string(&mut got).await.unwrap();
+            assert_eq!("* PREAUTH welcome\r\nA1 OK PREAUTH\r\n", got);
+            // Only the login was kept from the server.
+            let sent = String::from_utf8_lossy(s.inner.get_ref());
+            assert_eq!("* PREAUTH welcome\r\nA2 NOOP\r\n", sent);
+        });
+    }
+
+    #[test]
+    fn test_normal() {
+        block_on(async {
+            let server = Cursor::new(b"* OK ready\r\n".to_vec());
+            let mut s = super::Preauth::new(server, true);
+            s.write_all(b"A1 LOGIN \"me\" \"secret\"\r\n")
+                .await
+                .unwrap();
+            s.flush().await.unwrap();
+            let mut got = String::new();
+            s.read_to_string(&mut got).await.unwrap();
+            assert_eq!("* OK ready\r\n", got);
+        });
+    }
+
+    #[test]
+    fn test_caps_code() {
+        assert_eq!(
+            Some(b"IMAP4rev1 IDLE".to_vec()),
+            super::caps_code(b"[CAPABILITY IMAP4rev1 IDLE] Logged in\r\n")
+        );
+        assert_eq!(None, super::caps_code(b"Logged in\r\n"));
+        assert_eq!(None, super::caps_code(b"[ALERT] Hi\r\n"));
+    }
+}
@@ -0,0 +1,306 @@
+use std::io;
+use std::net::IpAddr;
+
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::net::TcpStream;
+
+use crate::session;
+
+/// A SOCKS5 proxy, from `--proxy socks5://[user:pass@]host:port`.
+///
+/// The IMAP host is sent by name, for the proxy to resolve: names only
+/// known past a bastion work, and nothing leaks through local DNS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub host: String,
+    pub port: u16,
+    /// Username and password, when the proxy asks for them.
+    pub auth: Option<(String, String)>,
+}
+
+impl std::str::FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Proxy, String> {
+        let rest = url
+            .strip_prefix("socks5://")
+            .or_else(|| url.strip_prefix("socks5h://"))
+            .ok_or_else(|| format!("expected a socks5:// URL, got {:?}", url))?;
+        let rest = rest.trim_end_matches('/');
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, pass) = auth.split_once(':').unwrap_or((auth, ""));
+                (Some((user.to_string(), pass.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing port in {:?}", url))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port {:?} in {:?}", port, url))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("missing host in {:?}", url));
+        }
+        Ok(Proxy {
+            host: host.into(),
+            port,
+            auth,
+        })
+    }
+}
+
+/// As a URL, without the password.
+impl serde::Serialize for Proxy {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let auth = match &self.auth {
+            Some((user, _)) => format!("{}:<redacted>@", user),
+            None => String::new(),
+        };
+        s.serialize_str(&format!("socks5://{}{}:{}", auth, self.host, self.port))
+    }
+}
+
+impl Proxy {
+    /// Connect to `host`, from the `bind` local address if given, through
+    /// the proxy. A refusal of either is an io error, for the usual retries.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        bind: Option<IpAddr>,
+    ) -> io::Result<TcpStream> {
+        let mut stream = session::connect(&self.host, self.port, bind)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("proxy {}: {}", self.host, e)))?;
+        handshake(&mut stream, host, port, self.auth.as_ref()).await?;
+        Ok(stream)
+    }
+
+    /// As `session::connect_any`, through the proxy: the first of `hosts`
+    /// it reaches, starting from the one at `start`, with its index.
+    pub async fn connect_any(
+        &self,
+        hosts: &[String],
+        start: usize,
+        port: u16,
+        bind: Option<IpAddr>,
+    ) -> io::Result<(usize, TcpStream)> {
+        let mut failure = None;
+        for i in (start..hosts.len()).chain(0..start) {
+            match self.connect(&hosts[i], port, bind).await {
+                Ok(stream) => return Ok((i, stream)),
+                Err(e) => {
+                    log::debug!(
+                        "Failure connecting to {} through the proxy: {}",
+                        hosts[i],
+                        e
+                    );
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| io::ErrorKind::InvalidInput.into()))
+    }
+}
+
+fn refused(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg.into())
+}
+
+/// The SOCKS5 greeting and CONNECT of RFC 1928, with the username and
+/// password of RFC 1929 when given.
+pub async fn handshake<S: Read + Write + Unpin>(
+    s: &mut S,
+    host: &str,
+    port: u16,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    const NO_AUTH: u8 = 0;
+    const USER_PASS: u8 = 2;
+    let method = if auth.is_some() { USER_PASS } else { NO_AUTH };
+    s.write_all(&[5, 1, method]).await?;
+    let mut reply = [0; 2];
+    s.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(refused(match auth {
+            Some(_) => "proxy refused username and password authentication",
+            None => "proxy requires authentication",
+        }));
+    }
+    if let Some((user, pass)) = auth {
+        if user.len() > 255 || pass.len() > 255 {
+            return Err(refused("proxy username or password over 255 bytes"));
+        }
+        let mut req = vec![1, user.len() as u8];
+        req.extend(user.as_bytes());
+        req.push(pass.len() as u8);
+        req.extend(pass.as_bytes());
+        s.write_all(&req).await?;
+        s.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(refused("proxy rejected the username and password"));
+        }
+    }
+
+    let mut req = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(1);
+            req.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(4);
+            req.extend(ip.octets());
+        }
+        Err(_) if host.len() > 255 => return Err(refused("host name over 255 bytes")),
+        Err(_) => {
+            req.extend([3, host.len() as u8]);
+            req.extend(host.as_bytes());
+        }
+    }
+    req.extend(port.to_be_bytes());
+    s.write_all(&req).await?;
+
+    let mut head = [0; 4];
+    s.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        let reason = match head[1] {
+            2 => "not allowed by ruleset",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            _ => "general failure",
+        };
+        return Err(refused(format!(
+            "proxy could not reach {}: {}",
+            host, reason
+        )));
+    }
+    // The address the proxy connected from, of no use here.
+    let len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0];
+            s.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        t => {
+            return Err(refused(format!(
+                "proxy replied an unknown address type {}",
+                t
+            )))
+        }
+    };
+    let mut bound = vec![0; len + 2];
+    s.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read, Write};
+
+    use async_std::task::block_on;
+
+    use super::Proxy;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(Proxy {
+                host: "localhost".into(),
+                port: 1080,
+                auth: None
+            }),
+            "socks5://localhost:1080".parse()
+        );
+        assert_eq!(
+            Ok(Proxy {
+                host: "::1".into(),
+                port: 9050,
+                auth: Some(("me".into(), "p:w".into()))
+            }),
+            "socks5h://me:p:w@[::1]:9050/".parse()
+        );
+        let proxy: Proxy = "socks5://me:pw@bastion:1080".parse().unwrap();
+        assert_eq!(
+            "\"socks5://me:<redacted>@bastion:1080\"",
+            serde_json::to_string(&proxy).unwrap()
+        );
+        assert!("http://localhost:8080".parse::<Proxy>().is_err());
+        assert!("socks5://localhost".parse::<Proxy>().is_err());
+    }
+
+    /// A proxy answering a connection with each of `replies` in turn,
+    /// returning what each was sent.
+    fn serve(replies: Vec<Vec<u8>>) -> (Proxy, std::thread::JoinHandle<Vec<Vec<u8>>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut sent = Vec::new();
+            for replies in replies {
+                let (mut conn, _) = listener.accept().unwrap();
+                conn.write_all(&replies).unwrap();
+                conn.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut got = Vec::new();
+                // Reset by a client giving up with replies left unread.
+                let _ = conn.read_to_end(&mut got);
+                sent.push(got);
+            }
+            sent
+        });
+        let proxy = Proxy {
+            host: "127.0.0.1".into(),
+            port,
+            auth: Some(("me".into(), "pw".into())),
+        };
+        (proxy, server)
+    }
+
+    #[test]
+    fn test_connect() {
+        let (proxy, server) = serve(vec![vec![5, 2, 1, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0, 25]]);
+        let stream = block_on(proxy.connect("imap.example.com", 993, None)).unwrap();
+        drop(stream);
+        let mut want = vec![5, 1, 2, 1, 2, b'm', b'e', 2, b'p', b'w', 5, 1, 0, 3, 16];
+        want.extend(b"imap.example.com");
+        want.extend([3, 225]);
+        assert_eq!(vec![want], server.join().unwrap());
+    }
+
+    #[test]
+    fn test_connect_any() {
+        let (proxy, server) = serve(vec![
+            vec![5, 2, 1, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0],
+            vec![5, 2, 1, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0, 25],
+        ]);
+        let hosts = ["dead.example.com".to_string(), "imap.example.com".into()];
+        let (i, stream) = block_on(proxy.connect_any(&hosts, 0, 993, None)).unwrap();
+        drop(stream);
+        assert_eq!(1, i);
+        let sent = server.join().unwrap();
+        assert!(sent[1].ends_with(b"imap.example.com\x03\xe1"), "{:?}", sent);
+    }
+
+    #[test]
+    fn test_refused() {
+        let (proxy, server) = serve(vec![vec![5, 2, 1, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]]);
+        let e = block_on(proxy.connect("10.0.0.1", 143, None)).unwrap_err();
+        assert_eq!(
+            "proxy could not reach 10.0.0.1: connection refused",
+            e.to_string()
+        );
+        server.join().unwrap();
+
+        let (proxy, server) = serve(vec![vec![5, 2, 1, 1]]);
+        let e = block_on(proxy.connect("10.0.0.1", 143, None)).unwrap_err();
+        assert_eq!("proxy rejected the username and password", e.to_string());
+        server.join().unwrap();
+    }
+}
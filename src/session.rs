@@ -0,0 +1,1625 @@
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_imap::error::Error as ImapError;
+use async_imap::imap_proto::{
+    AttributeValue, Envelope, MailboxDatum, Response, Status as ImapStatus,
+};
+use async_imap::types::{Mailbox, NameAttribute, Quota};
+use async_imap::{Client, Session};
+use async_std::io::{Read, Write};
+use async_std::net::{TcpStream, ToSocketAddrs};
+use async_std::os::unix::net::UnixStream;
+use async_std::prelude::*;
+
+use crate::auth::{self, AuthMethod};
+use crate::caps::{Caps, ServerCaps};
+use crate::config::{ColorRule, Folder, Watch};
+use crate::creds::Creds;
+use crate::errors::{self, Res};
+use crate::format;
+use crate::output::Status;
+use crate::state::{FolderState, SeenCount, State};
+
+/// How the connection gets encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// TLS from the start, usually on port 993
+    Implicit,
+    /// Plaintext upgraded with STARTTLS, usually on port 143
+    Starttls,
+}
+
+impl TlsMode {
+    /// The mode usual on `port`: STARTTLS on the plaintext IMAP port,
+    /// implicit TLS anywhere else.
+    pub fn for_port(port: u16) -> TlsMode {
+        match port {
+            143 => TlsMode::Starttls,
+            _ => TlsMode::Implicit,
+        }
+    }
+}
+
+/// Lowest TLS version accepted with `--tls-min-version`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+/// A TLS connector refusing versions below `min`, if given. The handshake
+/// then fails rather than downgrade.
+///
+/// The PEM certificates of `ca_cert` are trusted on top of the system's, for
+/// servers with a private CA. With `insecure`, any certificate is, for any
+/// name.
+pub fn tls_connector(
+    min: Option<TlsVersion>,
+    ca_cert: Option<&std::path::Path>,
+    insecure: bool,
+    client_cert: Option<(&std::path::Path, &str)>,
+) -> Res<async_native_tls::TlsConnector> {
+    let mut tls = async_native_tls::TlsConnector::new();
+    if insecure {
+        tls = tls
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    tls = match min {
+        Some(TlsVersion::V1_2) => tls.min_protocol_version(Some(native_tls::Protocol::Tlsv12)),
+        Some(TlsVersion::V1_3) => tls.min_protocol_version(Some(native_tls::Protocol::Tlsv13)),
+        None => tls,
+    };
+    if let Some(path) = ca_cert {
+        let pem = std::fs::read(path)
+            .map_err(|e| format!("cannot read --ca-cert {}: {}", path.display(), e))?;
+        let cert = native_tls::Certificate::from_pem(&pem)
+            .map_err(|e| format!("invalid --ca-cert {}: {}", path.display(), e))?;
+        tls = tls.add_root_certificate(cert);
+    }
+    if let Some((path, pass)) = client_cert {
+        let der = std::fs::read(path)
+            .map_err(|e| format!("cannot read --client-cert {}: {}", path.display(), e))?;
+        let identity = native_tls::Identity::from_pkcs12(&der, pass)
+            .map_err(|e| format!("invalid --client-cert {}: {}", path.display(), e))?;
+        tls = tls.identity(identity);
+    }
+    Ok(tls)
+}
+
+/// A connection to the server, over TLS, to a local unix socket or through a
+/// WebSocket.
+#[derive(Debug)]
+pub enum Stream {
+    Tls(async_native_tls::TlsStream<TcpStream>),
+    Unix(UnixStream),
+    #[cfg(feature = "websocket")]
+    Ws(Box<crate::ws::WsStream>),
+}
+
+impl Read for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tls(s) => Pin::new(s).poll_close(cx),
+            Stream::Unix(s) => Pin::new(s).poll_close(cx),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+/// Open a TCP connection, from the `bind` local address if given, to each
+/// address of `host` in turn until one answers, such as IPv4 after an
+/// unreachable IPv6.
+pub async fn connect(host: &str, port: u16, bind: Option<IpAddr>) -> std::io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .await?
+        .filter(|a| bind.is_none_or(|b| a.is_ipv4() == b.is_ipv4()))
+        .collect();
+    if let (Some(bind), true) = (bind, addrs.is_empty()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address of the same family as {}", host, bind),
+        ));
+    }
+    connect_addrs(&addrs, bind).await
+}
+
+/// Connect to the first of `addrs` that answers, or fail as the last did.
+async fn connect_addrs(addrs: &[SocketAddr], bind: Option<IpAddr>) -> std::io::Result<TcpStream> {
+    let mut failure = None;
+    for &addr in addrs {
+        let attempt = match bind {
+            Some(bind) => connect_from(addr, bind).await,
+            None => TcpStream::connect(addr).await,
+        };
+        match attempt {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                log::debug!("Failure connecting to {}: {}", addr, e);
+                failure = Some(e);
+            }
+        }
+    }
+    Err(failure.unwrap_or_else(|| std::io::ErrorKind::AddrNotAvailable.into()))
+}
+
+/// Connect to `addr` from the `bind` local address.
+async fn connect_from(addr: SocketAddr, bind: IpAddr) -> std::io::Result<TcpStream> {
+    let stream = async_std::task::spawn_blocking(move || {
+        let sock = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            None,
+        )?;
+        sock.bind(&SocketAddr::new(bind, 0).into()).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("cannot bind to {}: {}", bind, e))
+        })?;
+        sock.connect(&addr.into())?;
+        Ok::<_, std::io::Error>(std::net::TcpStream::from(sock))
+    })
+    .await?;
+    Ok(stream.into())
+}
+
+/// Open a TCP connection to the first of `hosts` that answers, starting from
+/// the one at `start` and wrapping around, returning its index along.
+pub async fn connect_any(
+    hosts: &[String],
+    start: usize,
+    port: u16,
+    bind: Option<IpAddr>,
+) -> std::io::Result<(usize, TcpStream)> {
+    let mut failure = None;
+    for i in (start..hosts.len()).chain(0..start) {
+        match connect(&hosts[i], port, bind).await {
+            Ok(stream) => return Ok((i, stream)),
+            Err(e) => {
+                log::debug!("Failure connecting to {}: {}", hosts[i], e);
+                failure = Some(e);
+            }
+        }
+    }
+    Err(failure.unwrap_or_else(|| std::io::ErrorKind::InvalidInput.into()))
+}
+
+/// Have the OS probe an idle connection after `idle`, then every third of
+/// it, giving up after three unanswered probes. A peer gone silently, such as
+/// behind an expired NAT mapping, then fails the pending read.
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(idle)
+        .with_interval((idle / 3).max(Duration::from_secs(1)))
+        .with_retries(3);
+    use std::os::fd::{AsRawFd, BorrowedFd};
+    // Safety: the fd stays open for as long as `stream` is borrowed.
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    socket2::SockRef::from(&fd).set_tcp_keepalive(&keepalive)
+}
+
+/// A plaintext stream keeping the last command written to it and what was
+/// read since, to tell whether anything came after the STARTTLS reply.
+#[derive(Debug)]
+struct LastLine<T> {
+    inner: T,
+    /// The last command line written.
+    sent: Vec<u8>,
+    /// Everything read since that command.
+    tail: Vec<u8>,
+}
+
+impl<T> LastLine<T> {
+    /// Whether the reads ended right after the tagged OK to the last
+    /// command, with not a byte past its CRLF.
+    fn tagged_end(&self) -> bool {
+        let tag = match self.sent.iter().position(|&b| b == b' ') {
+            Some(i) if i > 0 => &self.sent[..=i],
+            _ => return false,
+        };
+        // Untagged lines may come first, the tagged one must be the last.
+        let start = if self.tail.starts_with(tag) {
+            0
+        } else {
+            let mut needle = b"\r\n".to_vec();
+            needle.extend_from_slice(tag);
+            match self.tail.windows(needle.len()).position(|w| w == needle) {
+                Some(i) => i + 2,
+                None => return false,
+            }
+        };
+        let line = &self.tail[start..];
+        let status = &line[tag.len()..];
+        line.ends_with(b"\r\n")
+            && line.windows(2).filter(|w| w == b"\r\n").count() == 1
+            && status.len() >= 2
+            && status[..2].eq_ignore_ascii_case(b"OK")
+            && matches!(status.get(2), Some(b' ') | Some(b'\r'))
+    }
+}
+
+impl<T: Read + Unpin> Read for LastLine<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.tail.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: Write + Unpin> Write for LastLine<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = std::task::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if this.sent.ends_with(b"\r\n") {
+            this.sent.clear();
+        }
+        this.sent.extend_from_slice(&buf[..n]);
+        this.tail.clear();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Issue STARTTLS on a plaintext connection, returning the stream to wrap in
+/// TLS. Capabilities are queried again once encrypted, as servers often hide
+/// some until then.
+///
+/// Nothing is sent past STARTTLS until its tagged OK. Anything the server
+/// sent after that OK would be read as if encrypted, so is refused: such
+/// lines can only have been injected on the way.
+pub async fn starttls<T>(stream: T) -> Res<T>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let mut c = Client::new(LastLine {
+        inner: stream,
+        sent: Vec::new(),
+        tail: Vec::new(),
+    });
+    let caps = Caps::query(&mut c).await?;
+    log::debug!("Pre-STARTTLS capabilities: {}", caps);
+    if !caps.has("STARTTLS") {
+        return Err("server does not advertise STARTTLS".into());
+    }
+    c.run_command_and_check_ok("STARTTLS", None).await?;
+    let stream = c.into_inner();
+    if !stream.tagged_end() {
+        return Err("server sent more after its STARTTLS reply, refusing the connection".into());
+    }
+    Ok(stream.inner)
+}
+
+/// Why a fresh connection could not be logged in.
+#[derive(Debug)]
+pub enum StartError {
+    /// No usable authentication method, trying again won't help.
+    Method(Box<dyn std::error::Error>),
+    /// The server refused the login.
+    Login(ImapError),
+    /// The server has too many of our connections open already, with its
+    /// reason.
+    Limit(String),
+}
+
+/// Why a poll failed.
+#[derive(Debug)]
+pub enum PollError {
+    /// EXAMINE got a NO for this folder, or a watch's SEARCH in it was
+    /// refused: it is missing or forbidden, and reconnecting won't help.
+    Missing { folder: String, reason: String },
+    /// Anything else, worth reconnecting for.
+    Imap(ImapError),
+}
+
+impl From<ImapError> for PollError {
+    fn from(e: ImapError) -> PollError {
+        PollError::Imap(e)
+    }
+}
+
+/// Log in on a fresh connection, returning the session and the server's
+/// capabilities. Those `cached` from a previous connection are trusted
+/// instead of asking again. An `Auto` method follows the credentials' own,
+/// if any.
+pub async fn start<T>(
+    mut c: Client<T>,
+    auth: AuthMethod,
+    cred: &mut Creds,
+    cached: Option<&ServerCaps>,
+) -> Result<(Session<T>, ServerCaps), StartError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let pre_caps = match cached {
+        Some(cached) => cached.pre.clone(),
+        None => match Caps::query(&mut c).await {
+            Ok(caps) => {
+                log::debug!("Pre-login capabilities: {}", caps);
+                caps
+            }
+            Err(e) if errors::connection_limit(&e.to_string()) => {
+                return Err(StartError::Limit(e.to_string()))
+            }
+            Err(e) => {
+                log::debug!("Failure listing pre-login caps: {}", e);
+                Caps::default()
+            }
+        },
+    };
+    let auth = match (auth, cred.auth) {
+        (AuthMethod::Auto, Some(own)) => own,
+        (auth, _) => auth,
+    };
+    let method = auth.resolve(&pre_caps).map_err(StartError::Method)?;
+    if method.is_oauth() {
+        if let Err(e) = cred.refresh_token() {
+            log::warn!("Failure refreshing token: {}", e);
+        }
+    }
+    let mut s =
+        auth::login(c, method, cred).await.map_err(|(e, _)| {
+            match errors::connection_limit(&e.to_string()) {
+                true => StartError::Limit(e.to_string()),
+                false => StartError::Login(e),
+            }
+        })?;
+    log::debug!("logged in successfully");
+
+    // Some servers send capabilities the parser chokes on, polling still works.
+    let caps = match cached {
+        Some(cached) => cached.post.clone(),
+        None => match s.capabilities().await {
+            Ok(cap) => Caps::from(&cap),
+            Err(e) => {
+                log::warn!("Failure listing caps, falling back to polling: {}", e);
+                Caps::default()
+            }
+        },
+    };
+    log::debug!("Server can IDLE: {}", caps.has("IDLE"));
+    let caps = ServerCaps {
+        pre: pre_caps,
+        post: caps,
+    };
+    Ok((s, caps))
+}
+
+/// Turn on QRESYNC when advertised, returning whether it is. Folders then
+/// report their HIGHESTMODSEQ on EXAMINE.
+pub async fn enable_qresync<T>(s: &mut Session<T>, caps: &Caps) -> bool
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    if !caps.has("QRESYNC") {
+        return false;
+    }
+    match s.run_command_and_check_ok("ENABLE QRESYNC").await {
+        Ok(()) => true,
+        Err(e) => {
+            log::debug!("Failure enabling QRESYNC: {}", e);
+            false
+        }
+    }
+}
+
+/// Selectable folders matching a LIST pattern.
+pub async fn list_folders<T>(s: &mut Session<T>, pattern: &str) -> Res<Vec<String>>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let mut names = Vec::new();
+    let mut list = s.list(Some(""), Some(&quote(pattern))).await?;
+    while let Some(name) = list.next().await {
+        let name = name?;
+        if !name.attributes().contains(&NameAttribute::NoSelect) {
+            names.push(name.name().to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// `arg` as a quoted string.
+///
+/// Like async-imap, arguments are sent as quoted strings, never literals:
+/// folder names are 7-bit modified UTF-7, so LITERAL+ has nothing to spare.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Move the read messages of `folder` to `target`, returning how many.
+///
+/// Without MOVE, they are copied then expunged, with UID EXPUNGE where UIDPLUS
+/// allows: a plain EXPUNGE also removes other messages flagged \Deleted.
+/// The folder is left selected read-write, until the next poll examines it.
+pub async fn archive_read<T>(
+    s: &mut Session<T>,
+    caps: &Caps,
+    folder: &str,
+    target: &str,
+) -> Res<usize>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    s.select(folder).await?;
+    let mut uids: Vec<u32> = s.uid_search("SEEN").await?.into_iter().collect();
+    if uids.is_empty() {
+        return Ok(0);
+    }
+    uids.sort_unstable();
+    let set = uids
+        .iter()
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    if caps.has("MOVE") {
+        s.uid_mv(&set, target).await?;
+        return Ok(uids.len());
+    }
+    // Unlike MOVE, sent as is.
+    s.uid_copy(&set, quote(target)).await?;
+    let mut store = s.uid_store(&set, "+FLAGS.SILENT (\\Deleted)").await?;
+    while let Some(res) = store.next().await {
+        res?;
+    }
+    drop(store);
+    if caps.has("UIDPLUS") {
+        let mut expunged = Box::pin(s.uid_expunge(&set).await?);
+        while let Some(res) = expunged.next().await {
+            res?;
+        }
+    } else {
+        let mut expunged = Box::pin(s.expunge().await?);
+        while let Some(res) = expunged.next().await {
+            res?;
+        }
+    }
+    Ok(uids.len())
+}
+
+/// Every folder on the server, along with its LIST attributes such as
+/// `\Noselect`.
+pub async fn list_all<T>(s: &mut Session<T>) -> Res<Vec<(String, Vec<String>)>>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let mut folders = Vec::new();
+    let mut list = s.list(Some(""), Some("*")).await?;
+    while let Some(name) = list.next().await {
+        let name = name?;
+        let attrs = name.attributes().iter().map(attribute_name).collect();
+        folders.push((name.name().to_string(), attrs));
+    }
+    Ok(folders)
+}
+
+/// Folders with a SPECIAL-USE role such as `\\Junk`, asking for the roles
+/// explicitly where LIST-EXTENDED allows. Empty when SPECIAL-USE isn't
+/// advertised.
+pub async fn special_use<T>(s: &mut Session<T>, caps: &Caps, role: &str) -> Res<Vec<String>>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    if !caps.has("SPECIAL-USE") {
+        return Ok(Vec::new());
+    }
+    let id = match caps.has("LIST-EXTENDED") {
+        true => {
+            s.run_command("LIST \"\" \"*\" RETURN (SPECIAL-USE)")
+                .await?
+        }
+        false => s.run_command("LIST \"\" \"*\"").await?,
+    };
+    let mut names = Vec::new();
+    loop {
+        let res = s.read_response().await.ok_or(ImapError::ConnectionLost)??;
+        match res.parsed() {
+            Response::MailboxData(MailboxDatum::List {
+                name_attributes,
+                name,
+                ..
+            }) if name_attributes
+                .iter()
+                .any(|a| attribute_name(a).eq_ignore_ascii_case(role)) =>
+            {
+                names.push(name.to_string())
+            }
+            Response::Done {
+                tag,
+                status,
+                information,
+                ..
+            } if *tag == id => {
+                return match status {
+                    ImapStatus::Ok => Ok(names),
+                    _ => {
+                        Err(format!("LIST failed: {}", information.as_deref().unwrap_or("")).into())
+                    }
+                };
+            }
+            _ => (),
+        }
+    }
+}
+
+fn attribute_name(a: &NameAttribute) -> String {
+    match a {
+        NameAttribute::NoInferiors => "\\Noinferiors".into(),
+        NameAttribute::NoSelect => "\\Noselect".into(),
+        NameAttribute::Marked => "\\Marked".into(),
+        NameAttribute::Unmarked => "\\Unmarked".into(),
+        NameAttribute::All => "\\All".into(),
+        NameAttribute::Archive => "\\Archive".into(),
+        NameAttribute::Drafts => "\\Drafts".into(),
+        NameAttribute::Flagged => "\\Flagged".into(),
+        NameAttribute::Junk => "\\Junk".into(),
+        NameAttribute::Sent => "\\Sent".into(),
+        NameAttribute::Trash => "\\Trash".into(),
+        NameAttribute::Extension(e) => e.to_string(),
+        a => format!("{:?}", a),
+    }
+}
+
+/// Encode a folder name to the modified UTF-7 of RFC 3501, as the server
+/// knows it: runs of non-ASCII as UTF-16 in base64 between `&` and `-`, and
+/// `&` itself as `&-`.
+pub fn encode_utf7(name: &str) -> String {
+    const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+    let mut out = String::with_capacity(name.len());
+    let mut units = Vec::new();
+    let flush = |units: &mut Vec<u16>, out: &mut String| {
+        if units.is_empty() {
+            return;
+        }
+        out.push('&');
+        let (mut bits, mut n) = (0u32, 0);
+        for u in units.drain(..) {
+            bits = bits << 16 | u as u32;
+            n += 16;
+            while n >= 6 {
+                n -= 6;
+                out.push(BASE64[(bits >> n & 63) as usize] as char);
+            }
+            bits &= (1 << n) - 1;
+        }
+        if n > 0 {
+            out.push(BASE64[(bits << (6 - n) & 63) as usize] as char);
+        }
+        out.push('-');
+    };
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut units, &mut out);
+            out.push(c);
+            if c == '&' {
+                out.push('-');
+            }
+        } else {
+            units.extend(c.encode_utf16(&mut [0; 2]).iter());
+        }
+    }
+    flush(&mut units, &mut out);
+    out
+}
+
+/// A folder name as given by the user, encoded for the server unless it
+/// already is, such as `INBOX.Gel&APY-scht` copied from a folder listing.
+pub fn mailbox_name(name: &str) -> String {
+    match name.is_ascii() && decode(name).is_some() {
+        true => name.into(),
+        false => encode_utf7(name),
+    }
+}
+
+/// Decode a folder name from the modified UTF-7 of RFC 3501, where `&...-`
+/// holds UTF-16 in base64 with `,` for `/`. Malformed names are kept as is.
+pub fn decode_utf7(name: &str) -> String {
+    decode(name).unwrap_or_else(|| name.into())
+}
+
+/// The modified UTF-7 name decoded, `None` if malformed.
+fn decode(name: &str) -> Option<String> {
+    fn sextet(c: u8) -> Option<u32> {
+        Some(match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b',' => 63,
+            _ => return None,
+        } as u32)
+    }
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('-')? + start;
+        let encoded = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        if encoded.is_empty() {
+            out.push('&');
+            continue;
+        }
+        let (mut bits, mut n, mut units) = (0u32, 0, Vec::new());
+        for c in encoded.bytes() {
+            bits = bits << 6 | sextet(c)?;
+            n += 6;
+            if n >= 16 {
+                n -= 16;
+                units.push((bits >> n) as u16);
+                bits &= (1 << n) - 1;
+            }
+        }
+        out.extend(
+            char::decode_utf16(units)
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?,
+        );
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// The flag telling a message without folder criteria is new.
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NewFlag {
+    /// Not \Seen yet
+    #[default]
+    Unseen,
+    /// Flagged \Recent, for servers not keeping \Seen. Many servers clear it
+    /// once a session has seen the message, even a read-only one.
+    Recent,
+}
+
+/// The command opening each folder for its counts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelectMode {
+    /// Read-only, leaving every flag as it is
+    #[default]
+    Examine,
+    /// Read-write, for servers giving stale counts to EXAMINE. Nothing is
+    /// ever stored, so no message gets \Seen, but \Recent is cleared.
+    Select,
+}
+
+/// How a poll queries the server, beyond which folders.
+#[derive(Debug, Default, Clone)]
+pub struct PollOptions {
+    /// SEARCH and FETCH by sequence number rather than UID, for servers with
+    /// broken UID handling. Sequence numbers shift as the mailbox changes,
+    /// and are what `uids` then holds.
+    pub use_seq: bool,
+    /// Also count messages with this keyword, such as `$Important`.
+    pub keyword: Option<String>,
+    /// Also count messages matching these criteria, such as `FLAGGED`.
+    pub flagged: Option<String>,
+    /// Extra counters, each one more EXAMINE and SEARCH.
+    pub watches: Vec<Watch>,
+    /// Reuse the counts of folders whose HIGHESTMODSEQ is unchanged, once
+    /// QRESYNC is enabled.
+    pub resync: bool,
+    /// Searched in order in each folder for the block color, up to the
+    /// best match so far.
+    pub color_rules: Vec<ColorRule>,
+    /// Also list the UIDs of new messages, for the control socket.
+    pub uids: bool,
+    /// Gmail search syntax new messages must also match, through X-GM-RAW.
+    pub gmail_query: Option<String>,
+    /// What new means in folders without criteria.
+    pub new_flag: NewFlag,
+    /// SEARCH criteria of folders without their own, sent verbatim, instead
+    /// of `new_flag`.
+    pub search: Option<String>,
+    /// Also ask the quota usage of the first folder, on servers with QUOTA.
+    pub quota: bool,
+    /// Count Gmail conversations rather than messages, on a connection read
+    /// through `gmail::ThreadIds`.
+    pub threads: bool,
+    /// Only count new messages received this recently, to the day.
+    pub new_window: Option<Duration>,
+    /// Sender and subject lines of up to this many of the newest new
+    /// messages, for the tooltip.
+    pub previews: usize,
+    /// Reuse the new count of folders holding as many messages as on the
+    /// last poll, instead of searching again.
+    pub cache_unseen: bool,
+    /// How folders are opened.
+    pub select: SelectMode,
+}
+
+/// The SEARCH criteria counted as new in `folder`, if not all unread.
+fn criteria(folder: &Folder, opts: &PollOptions) -> Option<String> {
+    let default = match (&opts.search, opts.new_flag) {
+        (Some(search), _) => Some(search.clone()),
+        (None, NewFlag::Unseen) => None,
+        (None, NewFlag::Recent) => Some("RECENT".to_string()),
+    };
+    let mut criteria = folder.criteria.clone().or(default);
+    if let Some(window) = opts.new_window {
+        let start = SystemTime::now().checked_sub(window);
+        let since = imap_date(start.unwrap_or(SystemTime::UNIX_EPOCH));
+        let c = criteria.as_deref().unwrap_or("UNSEEN");
+        criteria = Some(format!("{} SINCE {}", c, since));
+    }
+    let Some(q) = &opts.gmail_query else {
+        return criteria;
+    };
+    let raw = format!(
+        "X-GM-RAW \"{}\"",
+        q.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let c = criteria.as_deref().unwrap_or("UNSEEN");
+    Some(format!("{} {}", c, raw))
+}
+
+/// The count of `criteria` when the EXAMINE answer tells it: none in an
+/// empty folder, the RECENT count, or a single unseen message when the first
+/// unseen is the last. Servers needn't give the first unseen, so otherwise
+/// it takes a SEARCH.
+fn examined(criteria: &str, mb: &Mailbox) -> Option<usize> {
+    match criteria {
+        _ if mb.exists == 0 => Some(0),
+        "RECENT" => Some(mb.recent as usize),
+        "UNSEEN" if mb.unseen == Some(mb.exists) => Some(1),
+        _ => None,
+    }
+}
+
+/// The UTC day of `t` as an IMAP date, such as `1-Jan-2024`.
+///
+/// SINCE compares the day of the internal date alone, in the server's
+/// timezone, so a window is only as precise as a day either way.
+fn imap_date(t: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = t
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // Days to a civil date, counting years from March so leap days come
+    // last.
+    let z = secs / 86400 + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{}-{}-{}", day, MONTHS[month as usize - 1], year)
+}
+
+/// The longest `--new-window`, ten years. Windows past it are rather typos.
+const MAX_WINDOW: u64 = 3650 * 86400;
+
+/// Parse a `--new-window` such as `24h` or `2d`, in seconds, minutes,
+/// hours or days.
+pub fn parse_window(w: &str) -> Result<Duration, String> {
+    let split = match w.char_indices().last() {
+        Some((i, c)) if c.is_alphabetic() => i,
+        _ => w.len(),
+    };
+    let (n, unit) = w.split_at(split);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("invalid window {}, expected such as 24h", w))?;
+    let secs = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("unknown unit {} in window {}", unit, w)),
+    };
+    match n.checked_mul(secs) {
+        Some(0) => Err("window is empty".into()),
+        Some(secs) if secs <= MAX_WINDOW => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("window {} is longer than ten years", w)),
+    }
+}
+
+/// Check a `--keyword` is a valid IMAP flag keyword: an atom, so no space,
+/// quote, wildcard or bracket.
+pub fn parse_keyword(k: &str) -> Result<String, String> {
+    let special = |c: char| c.is_ascii_control() || "(){ %*\"\\]".contains(c);
+    match k {
+        "" => Err("keyword is empty".into()),
+        k if k.starts_with('\\') => Err(format!("{} is a system flag, not a keyword", k)),
+        k if !k.is_ascii() || k.contains(special) => Err(format!("invalid keyword {}", k)),
+        k => Ok(k.into()),
+    }
+}
+
+/// Gather the counts of all folders, and the unread of the junk folder.
+///
+/// Watches come first and folders are examined in reverse, so that the first
+/// folder stays selected for IDLE. A watch rejected by the server is reported
+/// as a missing folder.
+/// Unread with the default criteria are counted with STATUS, sparing a SEARCH
+/// listing ids in huge mailboxes: the ESEARCH COUNT answer is rejected by the
+/// response parser. A keyword the server rejects is left uncounted, with a
+/// warning.
+pub async fn poll<T>(
+    s: &mut Session<T>,
+    folders: &[Folder],
+    junk_folder: Option<&str>,
+    opts: &PollOptions,
+    state: &mut State,
+) -> Result<Status, PollError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let mut t = Timings::default();
+    let ids = Numbering::of(opts);
+    // STATUS shouldn't target the selected mailbox. The first folder is left
+    // selected by the previous poll and IDLE; when nothing is yet, counting
+    // it by SEARCH after EXAMINE is only less thrifty.
+    let mut selected = folders.first().map(|f| f.name.clone());
+
+    let mut watched = Vec::with_capacity(opts.watches.len());
+    for w in &opts.watches {
+        missing_on_no(
+            &w.folder,
+            timed(&mut t.examine, open(s, &w.folder, opts.select)).await,
+        )?;
+        selected = Some(w.folder.clone());
+        let command = ids.command(&format!("SEARCH {}", w.criteria));
+        let n = match timed(&mut t.search, count_search(s, &command)).await {
+            Ok(n) => n,
+            Err(ImapError::No(reason) | ImapError::Bad(reason)) => {
+                return Err(PollError::Missing {
+                    folder: w.folder.clone(),
+                    reason,
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        watched.push((w.name.clone(), n));
+    }
+
+    let mut count = 0;
+    let mut recent = 0;
+    let mut new_count = 0;
+    let mut keyword = opts.keyword.as_ref().map(|_| 0);
+    let mut flagged = opts.flagged.as_ref().map(|_| 0);
+    // Index of the first color rule matched so far.
+    let mut best_rule = opts.color_rules.len();
+    let mut uids = opts.uids.then(BTreeMap::new);
+    let mut by_folder = BTreeMap::new();
+    // By folder index, as they are polled in reverse.
+    let mut previews = BTreeMap::new();
+    // Reused counts would leave the keyword, flags, rules or UIDs unchecked
+    // in those folders, previews not fetched, or a window not moved.
+    let resync = opts.resync
+        && opts.keyword.is_none()
+        && opts.flagged.is_none()
+        && opts.color_rules.is_empty()
+        && !opts.uids
+        && opts.previews == 0
+        && opts.new_window.is_none();
+    // A moving window changes the count with no new message.
+    let cache_unseen = opts.cache_unseen && opts.new_window.is_none();
+    for (i, folder) in folders.iter().enumerate().rev() {
+        let criteria = criteria(folder, opts);
+        let cached = state
+            .folders
+            .get(&folder.name)
+            .filter(|c| resync && c.counts(criteria.as_deref(), opts.threads))
+            .cloned();
+        let is_selected = selected.as_deref() == Some(folder.name.as_str());
+        // All but the first, which IDLE needs selected.
+        if let (Some(c), true) = (&cached, i > 0 && !is_selected) {
+            let status = timed(
+                &mut t.status,
+                s.status(&folder.name, "(UIDVALIDITY HIGHESTMODSEQ)"),
+            )
+            .await;
+            if c.current(&missing_on_no(&folder.name, status)?) {
+                log::debug!("{} unchanged since modseq {}", folder.name, c.modseq);
+                count += c.exists;
+                recent += c.recent;
+                new_count += c.new_count;
+                by_folder.insert(folder.name.clone(), c.new_count);
+                continue;
+            }
+        }
+        let mut new = 0;
+        // Before EXAMINE selects it.
+        let by_status = criteria.is_none() && !cache_unseen && !is_selected;
+        if by_status {
+            new = timed(&mut t.status, unseen(s, &folder.name)).await?;
+        }
+        let examine = timed(&mut t.examine, open(s, &folder.name, opts.select)).await;
+        let mb = missing_on_no(&folder.name, examine)?;
+        selected = Some(folder.name.clone());
+        count += mb.exists;
+        recent += mb.recent;
+        let seen = state.unseen.get(&folder.name).filter(|_| cache_unseen);
+        if let Some(c) = seen.filter(|c| c.current(&mb)) {
+            log::debug!(
+                "{} still holds {} messages, not searching again",
+                folder.name,
+                mb.exists
+            );
+            new = c.new_count;
+        } else {
+            if let Some(criteria) = &criteria {
+                let examined = examined(criteria, &mb);
+                new = match (&cached, examined) {
+                    (Some(c), _) if c.current(&mb) => c.new_count,
+                    (_, Some(n)) => {
+                        log::debug!("{}: {} {} as told by EXAMINE", folder.name, n, criteria);
+                        n
+                    }
+                    (_, None) => timed(&mut t.search, ids.search(s, criteria)).await?.len(),
+                };
+            } else if !by_status {
+                new = match examined("UNSEEN", &mb) {
+                    Some(n) => n,
+                    None => {
+                        let command = ids.command("SEARCH UNSEEN");
+                        timed(&mut t.search, count_search(s, &command)).await?
+                    }
+                };
+            }
+            if opts.threads && new > 0 {
+                let criteria = criteria.as_deref().unwrap_or("UNSEEN");
+                new = timed(&mut t.search, threads(s, ids, criteria)).await?;
+            }
+        }
+        if cache_unseen {
+            let seen = SeenCount {
+                uid_validity: mb.uid_validity,
+                exists: mb.exists,
+                new_count: new,
+            };
+            state.unseen.insert(folder.name.clone(), seen);
+        }
+        new_count += new;
+        by_folder.insert(folder.name.clone(), new);
+        if resync {
+            match FolderState::new(&mb, new, criteria.as_deref(), opts.threads) {
+                Some(fs) => state.folders.insert(folder.name.clone(), fs),
+                None => state.folders.remove(&folder.name),
+            };
+        }
+        if let Some(uids) = &mut uids {
+            let criteria = criteria.as_deref().unwrap_or("UNSEEN");
+            let found = timed(&mut t.search, ids.search(s, criteria)).await?;
+            let mut found: Vec<u32> = found.into_iter().collect();
+            found.sort_unstable();
+            uids.insert(folder.name.clone(), found);
+        }
+        if opts.previews > 0 && new > 0 {
+            let criteria = criteria.as_deref().unwrap_or("UNSEEN");
+            let found = timed(&mut t.search, ids.search(s, criteria)).await?;
+            let lines = timed(&mut t.search, fetch_previews(s, ids, found, opts.previews)).await?;
+            previews.insert(i, lines);
+        }
+        for (i, rule) in opts.color_rules[..best_rule].iter().enumerate() {
+            if !timed(&mut t.search, ids.search(s, &rule.criteria))
+                .await?
+                .is_empty()
+            {
+                best_rule = i;
+                break;
+            }
+        }
+        if let (Some(k), Some(n)) = (&opts.keyword, &mut keyword) {
+            let command = ids.command(&format!("SEARCH KEYWORD {}", k));
+            match timed(&mut t.search, count_search(s, &command)).await {
+                Ok(c) => *n += c,
+                Err(ImapError::No(e) | ImapError::Bad(e)) => {
+                    log::warn!("Server rejected keyword {}: {}", k, e);
+                    keyword = None;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if let (Some(c), Some(n)) = (&opts.flagged, &mut flagged) {
+            let command = ids.command(&format!("SEARCH {}", c));
+            *n += timed(&mut t.search, count_search(s, &command)).await?;
+        }
+    }
+
+    // Last, not to STATUS a folder polled while it is selected.
+    let junk = match junk_folder {
+        Some(folder) if selected.as_deref() == Some(folder) => {
+            let command = ids.command("SEARCH UNSEEN");
+            Some(timed(&mut t.search, count_search(s, &command)).await?)
+        }
+        Some(folder) => Some(timed(&mut t.status, unseen(s, folder)).await?),
+        None => None,
+    };
+
+    // Not a selection, so the first folder stays selected for IDLE.
+    let quota = match (opts.quota, folders.first()) {
+        (true, Some(f)) => timed(&mut t.status, quota(s, &f.name)).await?,
+        _ => None,
+    };
+
+    log::debug!(
+        "poll took status={}ms examine={}ms search={}ms",
+        t.status.as_millis(),
+        t.examine.as_millis(),
+        t.search.as_millis()
+    );
+    Ok(Status {
+        new_count,
+        count,
+        recent,
+        junk,
+        keyword,
+        flagged,
+        watched,
+        color: opts.color_rules.get(best_rule).map(|r| r.color.clone()),
+        uids,
+        by_folder,
+        quota,
+        previews: previews
+            .into_values()
+            .flatten()
+            .take(opts.previews)
+            .collect(),
+        delta: 0,
+        last_success: Instant::now(),
+        checked_at: std::time::SystemTime::now(),
+        tooltip: None,
+    })
+}
+
+/// Time spent by a poll on each kind of command, to tell which is slow.
+#[derive(Default)]
+struct Timings {
+    status: Duration,
+    examine: Duration,
+    search: Duration,
+}
+
+/// Await `f`, adding the time it took to `total`.
+async fn timed<F: Future>(total: &mut Duration, f: F) -> F::Output {
+    let start = Instant::now();
+    let out = f.await;
+    *total += start.elapsed();
+    out
+}
+
+/// Run a SEARCH, counting the results. Unlike `Session::search`, a NO or BAD
+/// answer is an error rather than an empty result.
+async fn count_search<T>(s: &mut Session<T>, command: &str) -> Result<usize, ImapError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let id = s.run_command(command).await?;
+    let mut count = 0;
+    loop {
+        let res = s.read_response().await.ok_or(ImapError::ConnectionLost)??;
+        match res.parsed() {
+            Response::MailboxData(MailboxDatum::Search(ids)) => count += ids.len(),
+            Response::Done {
+                tag,
+                status,
+                information,
+                ..
+            } if *tag == id => {
+                let info = information.as_deref().unwrap_or_default().to_string();
+                return match status {
+                    ImapStatus::Ok => Ok(count),
+                    ImapStatus::No => Err(ImapError::No(info)),
+                    _ => Err(ImapError::Bad(info)),
+                };
+            }
+            // Unsolicited updates, the next poll picks them up.
+            _ => (),
+        }
+    }
+}
+
+/// Percentage used of the quota closest to its limit among those of
+/// `folder`'s roots. None when the server sets no limit, or refuses.
+async fn quota<T>(s: &mut Session<T>, folder: &str) -> Result<Option<u32>, ImapError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    // A NO comes back as no quota at all.
+    let (_, quotas) = s.get_quota_root(folder).await?;
+    Ok(usage(&quotas))
+}
+
+fn usage(quotas: &[Quota]) -> Option<u32> {
+    quotas
+        .iter()
+        .flat_map(|q| &q.resources)
+        .filter(|r| r.limit > 0)
+        .map(|r| (r.usage.saturating_mul(100) / r.limit) as u32)
+        .max()
+}
+
+/// Distinct Gmail conversations among the messages matching `criteria`.
+///
+/// Their X-GM-THRID come back as X-GM-MSGID, through `gmail::ThreadIds`.
+async fn threads<T>(s: &mut Session<T>, ids: Numbering, criteria: &str) -> Result<usize, ImapError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let found = ids.search(s, criteria).await?;
+    if found.is_empty() {
+        return Ok(0);
+    }
+    let set = found
+        .iter()
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let command = ids.command(&format!("FETCH {} (X-GM-THRID)", set));
+    let id = s.run_command(&command).await?;
+    let mut threads = HashSet::new();
+    loop {
+        let res = s.read_response().await.ok_or(ImapError::ConnectionLost)??;
+        match res.parsed() {
+            Response::Fetch(_, attrs) => threads.extend(attrs.iter().filter_map(|a| match a {
+                AttributeValue::GmailMsgId(t) => Some(*t),
+                _ => None,
+            })),
+            Response::Done {
+                tag,
+                status,
+                information,
+                ..
+            } if *tag == id => {
+                let info = information.as_deref().unwrap_or_default().to_string();
+                return match status {
+                    ImapStatus::Ok => Ok(threads.len()),
+                    ImapStatus::No => Err(ImapError::No(info)),
+                    _ => Err(ImapError::Bad(info)),
+                };
+            }
+            _ => (),
+        }
+    }
+}
+
+/// `From — Subject` lines of the newest of `found`, up to `max`, newest
+/// first.
+async fn fetch_previews<T>(
+    s: &mut Session<T>,
+    ids: Numbering,
+    found: HashSet<u32>,
+    max: usize,
+) -> Result<Vec<String>, ImapError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let mut found: Vec<u32> = found.into_iter().collect();
+    found.sort_unstable_by_key(|&u| std::cmp::Reverse(u));
+    found.truncate(max);
+    if found.is_empty() {
+        return Ok(Vec::new());
+    }
+    let set = found
+        .iter()
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let id = s
+        .run_command(&ids.command(&format!("FETCH {} ENVELOPE", set)))
+        .await?;
+    let mut lines = Vec::new();
+    loop {
+        let res = s.read_response().await.ok_or(ImapError::ConnectionLost)??;
+        match res.parsed() {
+            Response::Fetch(seq, attrs) => {
+                let uid = attrs.iter().find_map(|a| match a {
+                    AttributeValue::Uid(u) => Some(*u),
+                    _ => None,
+                });
+                let envelope = attrs.iter().find_map(|a| match a {
+                    AttributeValue::Envelope(e) => Some(e),
+                    _ => None,
+                });
+                let n = match ids {
+                    Numbering::Uid => uid,
+                    Numbering::Seq => Some(*seq),
+                };
+                // Unasked for, as flag updates may come unsolicited.
+                if let (Some(n), Some(envelope)) = (n.filter(|n| found.contains(n)), envelope) {
+                    lines.push((n, preview(envelope)));
+                }
+            }
+            Response::Done {
+                tag,
+                status,
+                information,
+                ..
+            } if *tag == id => {
+                let info = information.as_deref().unwrap_or_default().to_string();
+                match status {
+                    ImapStatus::Ok => break,
+                    ImapStatus::No => return Err(ImapError::No(info)),
+                    _ => return Err(ImapError::Bad(info)),
+                }
+            }
+            _ => (),
+        }
+    }
+    lines.sort_unstable_by_key(|(n, _)| std::cmp::Reverse(*n));
+    Ok(lines.into_iter().map(|(_, line)| line).collect())
+}
+
+/// The sender, by name if given, and subject of a message.
+fn preview(envelope: &Envelope) -> String {
+    let lossy = |b: &[u8]| String::from_utf8_lossy(b).into_owned();
+    let from = envelope.from.as_ref().and_then(|f| f.first());
+    let from = match from.map(|a| (&a.name, &a.mailbox, &a.host)) {
+        Some((Some(name), _, _)) => format::decode_header(name),
+        Some((None, Some(mailbox), Some(host))) => format!("{}@{}", lossy(mailbox), lossy(host)),
+        _ => "unknown sender".into(),
+    };
+    let subject = envelope.subject.as_deref().map(format::decode_header);
+    format!("{} — {}", from, subject.unwrap_or_default())
+}
+
+/// How poll commands refer to messages: by UID, or by sequence number with
+/// `use_seq`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Numbering {
+    Uid,
+    Seq,
+}
+
+impl Numbering {
+    fn of(opts: &PollOptions) -> Numbering {
+        match opts.use_seq {
+            true => Numbering::Seq,
+            false => Numbering::Uid,
+        }
+    }
+
+    /// A SEARCH or FETCH `command` in this numbering.
+    fn command(self, command: &str) -> String {
+        match self {
+            Numbering::Uid => format!("UID {}", command),
+            Numbering::Seq => command.into(),
+        }
+    }
+
+    /// The messages matching `criteria`.
+    async fn search<T>(self, s: &mut Session<T>, criteria: &str) -> Result<HashSet<u32>, ImapError>
+    where
+        T: Read + Write + Unpin + std::fmt::Debug + Send,
+    {
+        match self {
+            Numbering::Uid => s.uid_search(criteria).await,
+            Numbering::Seq => s.search(criteria).await,
+        }
+    }
+}
+
+/// Unread messages of a folder, counted by the server.
+async fn unseen<T>(s: &mut Session<T>, folder: &str) -> Result<usize, PollError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    let mb = missing_on_no(folder, s.status(folder, "(UNSEEN)").await)?;
+    Ok(mb.unseen.unwrap_or(0) as usize)
+}
+
+/// EXAMINE or SELECT `folder`, as `mode` says.
+async fn open<T>(s: &mut Session<T>, folder: &str, mode: SelectMode) -> Result<Mailbox, ImapError>
+where
+    T: Read + Write + Unpin + std::fmt::Debug + Send,
+{
+    match mode {
+        SelectMode::Examine => {
+            log::debug!("EXAMINE {}", folder);
+            s.examine(folder).await
+        }
+        SelectMode::Select => {
+            log::debug!("SELECT {}", folder);
+            s.select(folder).await
+        }
+    }
+}
+
+/// A NO about a folder means it is missing or forbidden.
+fn missing_on_no<R>(folder: &str, res: Result<R, ImapError>) -> Result<R, PollError> {
+    match res {
+        Ok(r) => Ok(r),
+        Err(ImapError::No(reason)) => Err(PollError::Missing {
+            folder: folder.into(),
+            reason,
+        }),
+        Err(e) => Err(PollError::Imap(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_imap::types::Mailbox;
+
+    #[test]
+    fn test_examined() {
+        let mb = |exists, recent, unseen| Mailbox {
+            exists,
+            recent,
+            unseen,
+            ..Default::default()
+        };
+        assert_eq!(Some(0), super::examined("UNSEEN FLAGGED", &mb(0, 0, None)));
+        assert_eq!(Some(2), super::examined("RECENT", &mb(42, 2, None)));
+        assert_eq!(Some(1), super::examined("UNSEEN", &mb(42, 0, Some(42))));
+        // Any of the messages past the first unseen may be unseen too.
+        assert_eq!(None, super::examined("UNSEEN", &mb(42, 0, Some(40))));
+        assert_eq!(None, super::examined("UNSEEN", &mb(42, 0, None)));
+        assert_eq!(
+            None,
+            super::examined("RECENT SINCE 1-Jan-2024", &mb(42, 2, None))
+        );
+    }
+
+    #[test]
+    fn test_tls_connector_ca() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.pem");
+        let e = super::tls_connector(None, Some(&missing), false, None).unwrap_err();
+        assert!(e.to_string().starts_with("cannot read --ca-cert"), "{}", e);
+
+        let garbage = dir.path().join("garbage.pem");
+        std::fs::write(&garbage, "not a certificate").unwrap();
+        let e = super::tls_connector(None, Some(&garbage), false, None).unwrap_err();
+        assert!(e.to_string().starts_with("invalid --ca-cert"), "{}", e);
+
+        super::tls_connector(None, None, false, None).unwrap();
+        super::tls_connector(None, None, true, None).unwrap();
+    }
+
+    #[test]
+    fn test_tls_connector_client_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.p12");
+        let e = super::tls_connector(None, None, false, Some((&missing, ""))).unwrap_err();
+        assert!(
+            e.to_string().starts_with("cannot read --client-cert"),
+            "{}",
+            e
+        );
+
+        let garbage = dir.path().join("garbage.p12");
+        std::fs::write(&garbage, "not a bundle").unwrap();
+        let e = super::tls_connector(None, None, false, Some((&garbage, "pw"))).unwrap_err();
+        assert!(e.to_string().starts_with("invalid --client-cert"), "{}", e);
+    }
+
+    #[test]
+    fn test_connect_addrs() {
+        async_std::task::block_on(async {
+            // Closed again, so nothing listens there.
+            let closed = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let refused = closed.local_addr().unwrap();
+            drop(closed);
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let open = listener.local_addr().unwrap();
+
+            let stream = super::connect_addrs(&[refused, open], None).await.unwrap();
+            assert_eq!(open, stream.peer_addr().unwrap());
+            let e = super::connect_addrs(&[refused], None).await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::ConnectionRefused, e.kind());
+            assert!(super::connect_addrs(&[], None).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_tls_for_port() {
+        use super::TlsMode;
+        assert_eq!(TlsMode::Starttls, TlsMode::for_port(143));
+        assert_eq!(TlsMode::Implicit, TlsMode::for_port(993));
+        assert_eq!(TlsMode::Implicit, TlsMode::for_port(1143));
+    }
+
+    #[test]
+    fn test_usage() {
+        use async_imap::types::{Quota, QuotaResource, QuotaResourceName};
+        let resource = |name, usage, limit| QuotaResource { name, usage, limit };
+        let quota = Quota {
+            root_name: "".into(),
+            resources: vec![
+                resource(QuotaResourceName::Storage, 850, 1000),
+                resource(QuotaResourceName::Message, 5, 100),
+                resource(QuotaResourceName::Atom("X-EMPTY".into()), 3, 0),
+            ],
+        };
+        assert_eq!(Some(85), super::usage(&[quota]));
+        assert_eq!(None, super::usage(&[]));
+    }
+
+    #[test]
+    fn test_encode_utf7() {
+        assert_eq!("INBOX", super::encode_utf7("INBOX"));
+        assert_eq!("[Gmail]/All Mail", super::encode_utf7("[Gmail]/All Mail"));
+        assert_eq!("INBOX.Gel&APY-scht", super::encode_utf7("INBOX.Gelöscht"));
+        assert_eq!("Entw&APw-rfe", super::encode_utf7("Entwürfe"));
+        assert_eq!("Tom &- Jerry", super::encode_utf7("Tom & Jerry"));
+        assert_eq!("&ZeVnLIqe-", super::encode_utf7("日本語"));
+        assert_eq!("&2D3eAA-", super::encode_utf7("😀"));
+        for name in ["Brouillons/Réponses", "Correo no deseado", "Σ&日本"] {
+            assert_eq!(name, super::decode_utf7(&super::encode_utf7(name)));
+        }
+
+        assert_eq!("INBOX.Gel&APY-scht", super::mailbox_name("INBOX.Gelöscht"));
+        assert_eq!(
+            "INBOX.Gel&APY-scht",
+            super::mailbox_name("INBOX.Gel&APY-scht")
+        );
+        assert_eq!("Tom &- Jerry", super::mailbox_name("Tom & Jerry"));
+    }
+
+    #[test]
+    fn test_decode_utf7() {
+        assert_eq!("INBOX", super::decode_utf7("INBOX"));
+        assert_eq!("Entwürfe", super::decode_utf7("Entw&APw-rfe"));
+        assert_eq!("Tom & Jerry", super::decode_utf7("Tom &- Jerry"));
+        assert_eq!("日本語", super::decode_utf7("&ZeVnLIqe-"));
+        assert_eq!("Broken &x", super::decode_utf7("Broken &x"));
+    }
+
+    #[test]
+    fn test_parse_keyword() {
+        assert_eq!(Ok("$Important".into()), super::parse_keyword("$Important"));
+        assert!(super::parse_keyword("").is_err());
+        assert!(super::parse_keyword("\\Seen").is_err());
+        assert!(super::parse_keyword("two words").is_err());
+        assert!(super::parse_keyword("x)").is_err());
+    }
+
+    #[test]
+    fn test_imap_date() {
+        use std::time::{Duration, SystemTime};
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!("1-Jan-1970", super::imap_date(at(0)));
+        assert_eq!("29-Feb-2024", super::imap_date(at(1709164800)));
+        assert_eq!("1-Mar-2024", super::imap_date(at(1709251199 + 1)));
+        assert_eq!("31-Dec-1999", super::imap_date(at(946684799)));
+    }
+
+    #[test]
+    fn test_criteria_window() {
+        use std::time::Duration;
+        let opts = super::PollOptions {
+            new_window: Some(Duration::from_secs(86400)),
+            ..Default::default()
+        };
+        let folder = crate::config::Folder {
+            name: "INBOX".into(),
+            criteria: None,
+        };
+        let c = super::criteria(&folder, &opts).unwrap();
+        assert!(c.starts_with("UNSEEN SINCE "), "{}", c);
+    }
+
+    #[test]
+    fn test_criteria_gmail_query() {
+        let opts = super::PollOptions {
+            gmail_query: Some("is:important".into()),
+            ..Default::default()
+        };
+        let folder = |criteria: Option<&str>| crate::config::Folder {
+            name: "INBOX".into(),
+            criteria: criteria.map(Into::into),
+        };
+        assert_eq!(
+            Some("UNSEEN X-GM-RAW \"is:important\""),
+            super::criteria(&folder(None), &opts).as_deref()
+        );
+        assert_eq!(
+            Some("FLAGGED X-GM-RAW \"is:important\""),
+            super::criteria(&folder(Some("FLAGGED")), &opts).as_deref()
+        );
+    }
+
+    #[test]
+    fn test_criteria_search() {
+        let opts = super::PollOptions {
+            search: Some("UNSEEN NOT HEADER List-Id \"\"".into()),
+            ..Default::default()
+        };
+        let folder = |criteria: Option<&str>| crate::config::Folder {
+            name: "INBOX".into(),
+            criteria: criteria.map(Into::into),
+        };
+        assert_eq!(
+            Some("UNSEEN NOT HEADER List-Id \"\""),
+            super::criteria(&folder(None), &opts).as_deref()
+        );
+        assert_eq!(
+            Some("FLAGGED"),
+            super::criteria(&folder(Some("FLAGGED")), &opts).as_deref()
+        );
+    }
+
+    #[test]
+    fn test_parse_window() {
+        use std::time::Duration;
+        assert_eq!(Ok(Duration::from_secs(86400)), super::parse_window("24h"));
+        assert_eq!(
+            Ok(Duration::from_secs(2 * 86400)),
+            super::parse_window("2d")
+        );
+        assert_eq!(Ok(Duration::from_secs(90)), super::parse_window("90"));
+        assert!(super::parse_window("0h").is_err());
+        assert!(super::parse_window("h").is_err());
+        assert!(super::parse_window("3w").is_err());
+        assert!(super::parse_window("").is_err());
+        assert!(super::parse_window("5é").is_err());
+        assert!(super::parse_window("18446744073709551615d").is_err());
+        assert!(super::parse_window("3651d").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use async_channel::Receiver;
+use async_signal::{Signal, Signals};
+use async_std::prelude::*;
+use async_std::task::{sleep, spawn};
+
+use crate::errors::Res;
+
+/// How long a session gets to log out once asked to stop, before exiting
+/// regardless, e.g. while stuck connecting.
+const GRACE: Duration = Duration::from_secs(5);
+/// The least time between refreshes, a burst of signals polling once.
+const REFRESH_GAP: Duration = Duration::from_secs(2);
+
+/// What a signal asks of the main loop.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// SIGTERM or SIGINT: log out and exit.
+    Shutdown,
+    /// SIGHUP: read the credentials again, and reconnect with them.
+    Reload,
+    /// SIGUSR1: poll now, without waiting for the server or the timer.
+    Refresh,
+}
+
+/// Handle SIGTERM, SIGINT, SIGHUP and SIGUSR1, passing them on to the main
+/// loop. A second SIGTERM or SIGINT, or the main loop not exiting within a
+/// few seconds, exits right away.
+pub fn listen() -> Res<Receiver<Action>> {
+    let mut signals = Signals::new([Signal::Term, Signal::Int, Signal::Hup, Signal::Usr1])?;
+    let (tx, rx) = async_channel::unbounded();
+    spawn(async move {
+        let mut stopping = false;
+        let mut refreshed: Option<Instant> = None;
+        while let Some(sig) = signals.next().await {
+            match sig {
+                Ok(Signal::Term | Signal::Int) if stopping => std::process::exit(0),
+                Ok(Signal::Term | Signal::Int) => {
+                    log::debug!("shutting down");
+                    stopping = true;
+                    let _ = tx.send(Action::Shutdown).await;
+                    spawn(async {
+                        sleep(GRACE).await;
+                        log::warn!("No clean logout after {}s, exiting", GRACE.as_secs());
+                        std::process::exit(0);
+                    });
+                }
+                Ok(Signal::Hup) => {
+                    log::debug!("reloading credentials");
+                    let _ = tx.send(Action::Reload).await;
+                }
+                Ok(Signal::Usr1) if refreshed.is_some_and(|at| at.elapsed() < REFRESH_GAP) => {
+                    log::debug!("ignoring refresh, one was just asked");
+                }
+                Ok(Signal::Usr1) => {
+                    log::debug!("refreshing");
+                    refreshed = Some(Instant::now());
+                    let _ = tx.send(Action::Refresh).await;
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Failure reading signals: {}", e),
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// The next action, or never once the handler is gone.
+pub async fn next(rx: &Receiver<Action>) -> Action {
+    match rx.recv().await {
+        Ok(action) => action,
+        Err(_) => std::future::pending().await,
+    }
+}
+
+/// The next shutdown, noting in `reload` any reload asked meanwhile, for
+/// waits that reconnect afterwards anyway. Refreshes have nothing to poll
+/// there.
+pub async fn shutdown(rx: &Receiver<Action>, reload: &Cell<bool>) {
+    loop {
+        match next(rx).await {
+            Action::Shutdown => return,
+            Action::Reload => reload.set(true),
+            Action::Refresh => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+
+    use async_std::task::block_on;
+
+    use super::Action;
+
+    #[test]
+    fn test_shutdown() {
+        let (tx, rx) = async_channel::unbounded();
+        tx.try_send(Action::Reload).unwrap();
+        tx.try_send(Action::Refresh).unwrap();
+        tx.try_send(Action::Shutdown).unwrap();
+        let reload = Cell::new(false);
+        block_on(super::shutdown(&rx, &reload));
+        assert!(reload.get());
+        assert!(rx.is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use async_imap::types::Mailbox;
+use async_std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::caps::ServerCaps;
+use crate::errors::Res;
+
+/// What is remembered across reconnects, and across restarts with
+/// `--state-file`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    /// Server the state was gathered from, none of it holds for another.
+    pub host: String,
+    /// Counts of each folder as of its last known modification sequence.
+    #[serde(default)]
+    pub folders: HashMap<String, FolderState>,
+    /// What the server advertised on the last fresh check.
+    #[serde(default)]
+    pub caps: Option<ServerCaps>,
+    /// New counts of each folder by size, with `--cache-unseen`. Not saved:
+    /// without a modification sequence, nothing tells them still right
+    /// after a restart.
+    #[serde(skip)]
+    pub unseen: HashMap<String, SeenCount>,
+}
+
+/// A folder's new count, reused while it holds as many messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeenCount {
+    pub uid_validity: Option<u32>,
+    pub exists: u32,
+    pub new_count: usize,
+}
+
+impl SeenCount {
+    /// Whether the folder kept its size since, going by an EXAMINE answer.
+    pub fn current(&self, mb: &Mailbox) -> bool {
+        mb.uid_validity == self.uid_validity && mb.exists == self.exists
+    }
+}
+
+/// A folder's counts, still valid while its UIDVALIDITY and HIGHESTMODSEQ
+/// are unchanged, and new messages are counted the same way.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FolderState {
+    pub uid_validity: u32,
+    pub modseq: u64,
+    pub exists: u32,
+    pub recent: u32,
+    pub new_count: usize,
+    /// The SEARCH criteria new messages were counted by, none for UNSEEN.
+    #[serde(default)]
+    pub criteria: Option<String>,
+    /// Whether threads were counted rather than messages.
+    #[serde(default)]
+    pub threads: bool,
+}
+
+impl FolderState {
+    /// The state of an examined folder, if the server tracks modifications.
+    pub fn new(
+        mb: &Mailbox,
+        new_count: usize,
+        criteria: Option<&str>,
+        threads: bool,
+    ) -> Option<FolderState> {
+        Some(FolderState {
+            uid_validity: mb.uid_validity?,
+            modseq: mb.highest_modseq?,
+            exists: mb.exists,
+            recent: mb.recent,
+            new_count,
+            criteria: criteria.map(String::from),
+            threads,
+        })
+    }
+
+    /// Whether new messages were counted as `criteria` and `threads` say,
+    /// the options of a restart may differ.
+    pub fn counts(&self, criteria: Option<&str>, threads: bool) -> bool {
+        self.criteria.as_deref() == criteria && self.threads == threads
+    }
+
+    /// Whether nothing changed since, going by a STATUS or EXAMINE answer.
+    pub fn current(&self, mb: &Mailbox) -> bool {
+        mb.uid_validity == Some(self.uid_validity) && mb.highest_modseq == Some(self.modseq)
+    }
+}
+
+impl State {
+    pub fn new(host: &str) -> State {
+        State {
+            host: host.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Read the state saved for `host`, starting afresh when there is none
+    /// or it was about another server.
+    pub async fn load(path: &Path, host: &str) -> State {
+        let state = match async_std::fs::read_to_string(path).await {
+            Ok(c) => serde_json::from_str::<State>(&c).map_err(|e| e.to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return State::new(host),
+            Err(e) => Err(e.to_string()),
+        };
+        match state {
+            Ok(state) if state.host == host => state,
+            Ok(_) => {
+                log::debug!("state file is about another server, ignoring it");
+                State::new(host)
+            }
+            Err(e) => {
+                log::warn!("Cannot read state file {}: {}", path.display(), e);
+                State::new(host)
+            }
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Res<()> {
+        let tmp = path.with_extension("tmp");
+        async_std::fs::write(&tmp, serde_json::to_string(self)?).await?;
+        async_std::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_load_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let path = async_std::path::Path::new(&path);
+        let mut state = block_on(super::State::load(path, "imap.example.com"));
+        assert!(state.folders.is_empty());
+
+        let folder = super::FolderState {
+            uid_validity: 7,
+            modseq: 100,
+            exists: 5,
+            recent: 0,
+            new_count: 2,
+            criteria: Some("FLAGGED".into()),
+            threads: false,
+        };
+        state.folders.insert("INBOX".into(), folder.clone());
+        block_on(state.save(path)).unwrap();
+
+        let state = block_on(super::State::load(path, "imap.example.com"));
+        assert_eq!(Some(&folder), state.folders.get("INBOX"));
+        let state = block_on(super::State::load(path, "other.example.com"));
+        assert!(state.folders.is_empty());
+    }
+}
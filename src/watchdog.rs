@@ -0,0 +1,53 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::task::{sleep, spawn};
+
+/// Tracks successful polls, feeding the systemd watchdog when running under
+/// one, and exiting when none happened within `max_stale`.
+pub struct Watchdog {
+    last: Arc<Mutex<Instant>>,
+    notify: bool,
+}
+
+impl Watchdog {
+    pub fn new(max_stale: Option<Duration>) -> Watchdog {
+        let last = Arc::new(Mutex::new(Instant::now()));
+        if let Some(max_stale) = max_stale {
+            let last = last.clone();
+            spawn(async move {
+                loop {
+                    let elapsed = last.lock().unwrap().elapsed();
+                    if elapsed >= max_stale {
+                        log::error!("No successful poll in {}s, giving up", elapsed.as_secs());
+                        std::process::exit(2);
+                    }
+                    sleep(max_stale - elapsed).await;
+                }
+            });
+        }
+        Watchdog {
+            last,
+            notify: std::env::var_os("NOTIFY_SOCKET").is_some(),
+        }
+    }
+
+    /// Hold off the stale check while polling is deliberately paused.
+    pub fn snooze(&self, d: Duration) {
+        *self.last.lock().unwrap() = Instant::now() + d;
+    }
+
+    /// When polling last succeeded, as shared with health checks.
+    pub fn last_success(&self) -> Arc<Mutex<Instant>> {
+        self.last.clone()
+    }
+
+    pub fn success(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+        if self.notify {
+            if let Err(e) = sd_notify::notify(&[sd_notify::NotifyState::Watchdog]) {
+                log::debug!("Failure pinging watchdog: {}", e);
+            }
+        }
+    }
+}
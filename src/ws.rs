@@ -0,0 +1,159 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_native_tls::{TlsConnector, TlsStream};
+use async_std::io::{Read, Write};
+use async_std::net::TcpStream;
+use async_tungstenite::tungstenite::http::Uri;
+use async_tungstenite::tungstenite::{Bytes, Message};
+use async_tungstenite::WebSocketStream;
+use futures::{Sink, Stream};
+
+use crate::errors::Res;
+use crate::session;
+
+/// IMAP tunnelled through a WebSocket, for networks only letting a proxy
+/// through.
+///
+/// Messages are read as one byte stream, each write is sent as a binary
+/// message. Pings are answered by the library and skipped here.
+#[derive(Debug)]
+pub struct WsStream<S = TlsStream<TcpStream>> {
+    ws: WebSocketStream<S>,
+    /// Rest of the last message, larger than the read buffer.
+    pending: Bytes,
+}
+
+/// Open a WebSocket to a `wss://` url, from the `bind` local address if
+/// given. The credentials go through, so plaintext `ws://` is refused.
+pub async fn connect(url: &str, tls: &TlsConnector, bind: Option<IpAddr>) -> Res<WsStream> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| format!("invalid --ws-url {}: {}", url, e))?;
+    if uri.scheme_str() != Some("wss") {
+        return Err(format!("--ws-url {} is not a wss:// URL", url).into());
+    }
+    let host = uri.host().unwrap_or_default();
+    let stream = session::connect(host, uri.port_u16().unwrap_or(443), bind).await?;
+    let stream = tls.connect(host, stream).await?;
+    let (ws, _) = async_tungstenite::client_async(url, stream).await?;
+    Ok(WsStream::new(ws))
+}
+
+impl<S> WsStream<S> {
+    pub fn new(ws: WebSocketStream<S>) -> WsStream<S> {
+        WsStream {
+            ws,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<S: Read + Write + Unpin> Read for WsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() {
+            this.pending = match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => data,
+                Some(Ok(Message::Text(text))) => text.into(),
+                // Control frames, not part of the stream.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            };
+        }
+        let n = buf.len().min(this.pending.len());
+        buf[..n].copy_from_slice(&this.pending.split_to(n));
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: Read + Write + Unpin> Write for WsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ws = &mut self.get_mut().ws;
+        ready!(Pin::new(&mut *ws).poll_ready(cx)).map_err(io::Error::other)?;
+        Pin::new(ws)
+            .start_send(Message::binary(buf.to_vec()))
+            .map_err(io::Error::other)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::prelude::*;
+    use async_std::task::{block_on, spawn};
+    use async_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn test_connect_plaintext() {
+        block_on(async {
+            let tls = async_native_tls::TlsConnector::new();
+            let e = super::connect("ws://127.0.0.1/imap", &tls, None)
+                .await
+                .unwrap_err();
+            assert!(e.to_string().contains("not a wss:// URL"), "{}", e);
+        });
+    }
+
+    #[test]
+    fn test_stream() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = async_tungstenite::accept_async(stream).await.unwrap();
+                ws.send(Message::Ping(Vec::new().into())).await.unwrap();
+                ws.send(Message::binary(b"* OK ".to_vec())).await.unwrap();
+                ws.send(Message::text("ready\r\n")).await.unwrap();
+                let got = loop {
+                    match ws.next().await.unwrap().unwrap() {
+                        Message::Pong(_) => continue,
+                        got => break got,
+                    }
+                };
+                ws.close(None).await.unwrap();
+                got
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let url = format!("ws://{}/imap", addr);
+            let (ws, _) = async_tungstenite::client_async(url, stream).await.unwrap();
+            let mut s = super::WsStream::new(ws);
+            let mut greeting = [0; 12];
+            s.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(b"* OK ready\r\n", &greeting);
+            s.write_all(b"A1 NOOP\r\n").await.unwrap();
+            s.flush().await.unwrap();
+            let mut rest = Vec::new();
+            s.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(Message::binary(b"A1 NOOP\r\n".to_vec()), server.await);
+        });
+    }
+}
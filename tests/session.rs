@@ -0,0 +1,1310 @@
+//! End-to-end runs of the session logic against a scripted IMAP server.
+
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_imap::Session;
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::UnixStream;
+use async_std::prelude::*;
+use async_std::task::{block_on, spawn};
+
+use imap_block::auth::AuthMethod;
+use imap_block::caps::Caps;
+use imap_block::config::{ColorRule, Folder, Watch};
+use imap_block::creds::Creds;
+use imap_block::errors;
+use imap_block::gmail::ThreadIds;
+use imap_block::idle::{self, Wake};
+use imap_block::output::Status;
+use imap_block::preauth::Preauth;
+use imap_block::session::{self, NewFlag, PollError, PollOptions, SelectMode, StartError};
+use imap_block::state::State;
+
+/// Modification state of every folder, which never changes.
+const MODSEQ: &str = "* OK [UIDVALIDITY 7] ok\r\n* OK [HIGHESTMODSEQ 100] ok\r\n";
+
+/// Reply to a single command, given the currently examined folder.
+fn reply(tag: &str, cmd: &str, arg: &str, selected: &mut String) -> String {
+    match cmd {
+        "CAPABILITY" => format!(
+            "* CAPABILITY IMAP4rev1 STARTTLS IDLE SPECIAL-USE\r\n{} OK done\r\n",
+            tag
+        ),
+        "LOGIN" if arg == "\"me\" \"secret\"" => format!("{} OK logged in\r\n", tag),
+        "LOGIN" if arg == "\"me\" \"caps\"" => {
+            format!(
+                "{} OK [CAPABILITY IMAP4rev1 IDLE QRESYNC] logged in\r\n",
+                tag
+            )
+        }
+        "LOGIN" if arg == "\"me\" \"busy\"" => {
+            format!("{} NO [LIMIT] Too many connections\r\n", tag)
+        }
+        "LOGIN" => format!("{} NO [AUTHENTICATIONFAILED] bad password\r\n", tag),
+        "EXAMINE" | "SELECT" => {
+            *selected = arg.trim_matches('"').into();
+            match selected.as_str() {
+                "INBOX" => format!(
+                    "* 5 EXISTS\r\n* 2 RECENT\r\n{}{} OK [READ-ONLY] done\r\n",
+                    MODSEQ, tag
+                ),
+                "Lists" => format!(
+                    "* 3 EXISTS\r\n* 0 RECENT\r\n{}{} OK [READ-ONLY] done\r\n",
+                    MODSEQ, tag
+                ),
+                _ => format!("{} NO [NONEXISTENT] no such mailbox\r\n", tag),
+            }
+        }
+        "STATUS" if arg.ends_with("(UIDVALIDITY HIGHESTMODSEQ)") => format!(
+            "* STATUS {} (UIDVALIDITY 7 HIGHESTMODSEQ 100)\r\n{} OK done\r\n",
+            arg.split(' ').next().unwrap(),
+            tag
+        ),
+        "STATUS" => match arg.split(' ').next().unwrap().trim_matches('"') {
+            "INBOX" => format!("* STATUS INBOX (UNSEEN 2)\r\n{} OK done\r\n", tag),
+            "Lists" => format!("* STATUS Lists (UNSEEN 1)\r\n{} OK done\r\n", tag),
+            _ => format!("{} NO [NONEXISTENT] no such mailbox\r\n", tag),
+        },
+        "SEARCH" => match selected.as_str() {
+            "INBOX" => format!("* SEARCH 1 4\r\n{} OK done\r\n", tag),
+            _ => format!("* SEARCH 2\r\n{} OK done\r\n", tag),
+        },
+        "UID" if arg == "SEARCH KEYWORD $Rejected" => {
+            format!("{} BAD unsupported keyword\r\n", tag)
+        }
+        "UID" if arg == "SEARCH DELETED" => format!("* SEARCH\r\n{} OK done\r\n", tag),
+        "UID"
+            if ["COPY ", "MOVE ", "STORE ", "EXPUNGE "]
+                .iter()
+                .any(|c| arg.starts_with(c)) =>
+        {
+            format!("{} OK done\r\n", tag)
+        }
+        "EXPUNGE" => format!("* 1 EXPUNGE\r\n{} OK done\r\n", tag),
+        "FETCH" if arg.ends_with(" ENVELOPE") => format!(
+            "* 4 FETCH (ENVELOPE (NIL \"=?UTF-8?Q?Caf=C3=A9?=\" ((\"Alice\" NIL \"alice\" \
+             \"example.com\")) NIL NIL NIL NIL NIL NIL NIL))\r\n{} OK done\r\n",
+            tag
+        ),
+        "UID" if arg.ends_with(" ENVELOPE") => format!(
+            "* 1 FETCH (UID 11 ENVELOPE (NIL \"Old news\" ((NIL NIL \"bob\" \"example.com\")) \
+             NIL NIL NIL NIL NIL NIL NIL))\r\n\
+             * 4 FETCH (UID 14 ENVELOPE (NIL \"=?UTF-8?Q?Caf=C3=A9?=\" ((\"Alice\" NIL \"alice\" \
+             \"example.com\")) NIL NIL NIL NIL NIL NIL NIL))\r\n{} OK done\r\n",
+            tag
+        ),
+        "UID" if arg.ends_with("(X-GM-THRID)") => match selected.as_str() {
+            "INBOX" => format!(
+                "* 1 FETCH (X-GM-THRID 111 UID 11)\r\n\
+                 * 4 FETCH (X-GM-THRID 111 UID 14)\r\n{} OK done\r\n",
+                tag
+            ),
+            _ => format!("* 2 FETCH (X-GM-THRID 222 UID 12)\r\n{} OK done\r\n", tag),
+        },
+        "UID" if arg.starts_with("SEARCH ") => match selected.as_str() {
+            "INBOX" => format!("* SEARCH 11 14\r\n{} OK done\r\n", tag),
+            _ => format!("* SEARCH 12\r\n{} OK done\r\n", tag),
+        },
+        "GETQUOTAROOT" => format!(
+            "* QUOTAROOT {} \"\"\r\n* QUOTA \"\" (STORAGE 850 1000)\r\n{} OK done\r\n",
+            arg, tag
+        ),
+        "LIST" => format!(
+            "* LIST (\\Noselect) \"/\" \"Archive\"\r\n\
+             * LIST (\\HasNoChildren \\Drafts) \"/\" \"Entw&APw-rfe\"\r\n\
+             {} OK done\r\n",
+            tag
+        ),
+        // Read elsewhere, reported while idle.
+        "IDLE" => "+ idling\r\n* 1 FETCH (FLAGS (\\Seen))\r\n".into(),
+        "STARTTLS" => format!("{} OK begin TLS\r\n", tag),
+        // Mail keeps arriving in Lists.
+        "NOOP" if selected == "Lists" => format!("* 4 EXISTS\r\n{} OK done\r\n", tag),
+        "NOOP" => format!("{} OK done\r\n", tag),
+        "LOGOUT" => format!("* BYE\r\n{} OK done\r\n", tag),
+        _ => format!("{} BAD unknown command\r\n", tag),
+    }
+}
+
+/// Serve one connection on a loopback port, returning a client stream to it.
+async fn serve() -> TcpStream {
+    serve_logged().await.0
+}
+
+/// As `serve`, along with the commands received so far.
+async fn serve_logged() -> (TcpStream, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let log = script(listener);
+    (TcpStream::connect(addr).await.unwrap(), log)
+}
+
+/// A session logged in to a fresh scripted server, along with the commands
+/// it received.
+async fn login() -> (Session<TcpStream>, Arc<Mutex<Vec<String>>>) {
+    let (stream, log) = serve_logged().await;
+    let c = async_imap::Client::new(stream);
+    let (s, _) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+        .await
+        .unwrap();
+    (s, log)
+}
+
+/// Poll `folders` once with `opts` on a fresh login, along with the commands
+/// sent.
+async fn poll_with(folders: &[Folder], opts: &PollOptions) -> (Status, Vec<String>) {
+    let (mut s, log) = login().await;
+    let status = session::poll(&mut s, folders, None, opts, &mut State::default())
+        .await
+        .unwrap();
+    let sent = log.lock().unwrap().clone();
+    (status, sent)
+}
+
+/// Answer the first connection to `listener` with `reply`, logging commands
+/// without their tag.
+fn script(listener: TcpListener) -> Arc<Mutex<Vec<String>>> {
+    script_greeting(listener, "* OK ready\r\n")
+}
+
+/// As `script`, greeting with `greeting`.
+fn script_greeting(listener: TcpListener, greeting: &'static str) -> Arc<Mutex<Vec<String>>> {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let received = log.clone();
+    spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        converse(stream, greeting, received).await;
+    });
+    log
+}
+
+/// Answer commands on `stream` until closed.
+async fn converse<S>(stream: S, greeting: &str, received: Arc<Mutex<Vec<String>>>)
+where
+    S: async_std::io::Read + async_std::io::Write + Clone + Unpin,
+{
+    let mut r = BufReader::new(stream.clone());
+    let mut w = stream;
+    w.write_all(greeting.as_bytes()).await.unwrap();
+    let mut selected = String::new();
+    let mut idle_tag = None;
+    let mut auth_tag = None;
+    let mut line = String::new();
+    while r.read_line(&mut line).await.unwrap_or(0) > 0 {
+        let l = line.trim_end();
+        let out = match (l, idle_tag.take(), auth_tag.take()) {
+            ("DONE", Some(tag), _) => format!("{} OK idle done\r\n", tag),
+            // user=me^Aauth=Bearer tok^A^A, in base64.
+            ("dXNlcj1tZQFhdXRoPUJlYXJlciB0b2sBAQ==", _, Some(tag)) => {
+                format!("{} OK authenticated\r\n", tag)
+            }
+            (_, _, Some(tag)) => format!("{} NO [AUTHENTICATIONFAILED] bad token\r\n", tag),
+            _ => {
+                let (tag, rest) = l.split_once(' ').unwrap();
+                received.lock().unwrap().push(rest.to_string());
+                let (cmd, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+                if cmd == "IDLE" {
+                    idle_tag = Some(tag.to_string());
+                }
+                if cmd == "AUTHENTICATE" {
+                    auth_tag = Some(tag.to_string());
+                    "+ \r\n".to_string()
+                } else {
+                    reply(tag, cmd, arg, &mut selected)
+                }
+            }
+        };
+        w.write_all(out.as_bytes()).await.unwrap();
+        line.clear();
+    }
+}
+
+fn creds(pass: &str) -> Creds {
+    Creds {
+        host: "127.0.0.1".into(),
+        port: 143,
+        user: "me".into(),
+        pass: pass.into(),
+        pass_cmd: None,
+        expires: None,
+        auth: None,
+    }
+}
+
+fn folder(name: &str) -> Folder {
+    Folder {
+        name: name.into(),
+        criteria: None,
+    }
+}
+
+#[test]
+fn test_poll() {
+    block_on(async {
+        let c = async_imap::Client::new(serve().await);
+        let (mut s, caps) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+            .await
+            .unwrap();
+        assert!(caps.post.has("IDLE"));
+
+        let status = session::poll(
+            &mut s,
+            &[folder("INBOX"), folder("Lists")],
+            Some("Lists"),
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, status.new_count);
+        assert_eq!(8, status.count);
+        assert_eq!(2, status.recent);
+        assert_eq!(Some(1), status.junk);
+        s.logout().await.unwrap();
+    });
+}
+
+#[test]
+fn test_preauth() {
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let log = script_greeting(listener, "* PREAUTH relay\r\n");
+        let stream = Preauth::new(TcpStream::connect(addr).await.unwrap(), true);
+        let c = async_imap::Client::new(stream);
+        // A wrong password, which the server never gets to reject.
+        let (mut s, _) = session::start(c, AuthMethod::Auto, &mut creds("wrong"), None)
+            .await
+            .unwrap();
+        let status = session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(2, status.new_count);
+        assert!(!log.lock().unwrap().iter().any(|c| c.starts_with("LOGIN")));
+    });
+}
+
+#[test]
+fn test_login_caps() {
+    block_on(async {
+        let (stream, log) = serve_logged().await;
+        let c = async_imap::Client::new(Preauth::new(stream, true));
+        let (mut s, caps) = session::start(c, AuthMethod::Login, &mut creds("caps"), None)
+            .await
+            .unwrap();
+        assert!(caps.post.has("QRESYNC"));
+        assert!(!caps.pre.has("QRESYNC"));
+        s.noop().await.unwrap();
+        let sent = log.lock().unwrap().clone();
+        assert_eq!(
+            1,
+            sent.iter().filter(|c| *c == "CAPABILITY").count(),
+            "{:?}",
+            sent
+        );
+        assert_eq!("NOOP", sent.last().unwrap());
+    });
+}
+
+#[test]
+fn test_unix_socket() {
+    block_on(async {
+        let (client, server) = UnixStream::pair().unwrap();
+        spawn(converse(server, "* OK ready\r\n", Default::default()));
+        let c = async_imap::Client::new(Preauth::new(session::Stream::Unix(client), true));
+        let (mut s, _) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+            .await
+            .unwrap();
+        let status = session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(2, status.new_count);
+    });
+}
+
+#[test]
+fn test_connect_bind() {
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        script(listener);
+        let stream = session::connect("127.0.0.1", port, Some([127, 0, 0, 1].into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::net::IpAddr::from([127, 0, 0, 1]),
+            stream.local_addr().unwrap().ip()
+        );
+
+        // Documentation range, never assigned locally.
+        let e = session::connect("127.0.0.1", port, Some([192, 0, 2, 1].into()))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("cannot bind to 192.0.2.1"), "{}", e);
+    });
+}
+
+#[test]
+fn test_connect_any() {
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Also loopback, where nothing listens.
+        let hosts = ["127.0.0.2".to_string(), "127.0.0.1".to_string()];
+        let (i, _) = session::connect_any(&hosts, 0, port, None).await.unwrap();
+        assert_eq!(1, i);
+        let (i, _) = session::connect_any(&hosts, 1, port, None).await.unwrap();
+        assert_eq!(1, i);
+        session::connect_any(&hosts[..1], 0, port, None)
+            .await
+            .unwrap_err();
+    });
+}
+
+#[test]
+fn test_keepalive() {
+    block_on(async {
+        let stream = serve().await;
+        session::set_keepalive(&stream, Duration::from_secs(60)).unwrap();
+        let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+        let sock = socket2::SockRef::from(&fd);
+        assert!(sock.keepalive().unwrap());
+        assert_eq!(Duration::from_secs(60), sock.tcp_keepalive_time().unwrap());
+        assert_eq!(
+            Duration::from_secs(20),
+            sock.tcp_keepalive_interval().unwrap()
+        );
+    });
+}
+
+#[test]
+fn test_xoauth2_from_creds() {
+    block_on(async {
+        let (stream, log) = serve_logged().await;
+        let c = async_imap::Client::new(stream);
+        let mut creds = Creds {
+            auth: Some(AuthMethod::Xoauth2),
+            ..creds("tok")
+        };
+        session::start(c, AuthMethod::Auto, &mut creds, None)
+            .await
+            .unwrap();
+        assert!(log
+            .lock()
+            .unwrap()
+            .contains(&"AUTHENTICATE XOAUTH2".to_string()));
+    });
+}
+
+#[test]
+fn test_starttls() {
+    block_on(async {
+        let (stream, log) = serve_logged().await;
+        let stream = session::starttls(stream).await.unwrap();
+        // Nothing goes out between STARTTLS and the handshake.
+        assert_eq!(
+            Some("STARTTLS"),
+            log.lock().unwrap().last().map(|c| c.as_str())
+        );
+        // The scripted server stays plaintext, the stream is left in sync.
+        let c = async_imap::Client::new(stream);
+        session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+            .await
+            .unwrap();
+    });
+}
+
+#[test]
+fn test_starttls_injected() {
+    // Untagged, tagged, and neither.
+    for injected in [
+        "* CAPABILITY IMAP4rev1 AUTH=PLAIN\r\n",
+        "A0003 OK x\r\n",
+        "foo\r\n",
+    ] {
+        starttls_injected(injected);
+    }
+}
+
+fn starttls_injected(injected: &'static str) {
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut r = BufReader::new(stream.clone());
+            let mut w = stream;
+            w.write_all(b"* OK ready\r\n").await.unwrap();
+            let mut line = String::new();
+            while r.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let (tag, cmd) = line.trim_end().split_once(' ').unwrap();
+                let out = match cmd {
+                    "CAPABILITY" => {
+                        format!("* CAPABILITY IMAP4rev1 STARTTLS\r\n{} OK done\r\n", tag)
+                    }
+                    // Sent along in plaintext, as by someone in the middle.
+                    _ => format!("{} OK begin TLS\r\n{}", tag, injected),
+                };
+                w.write_all(out.as_bytes()).await.unwrap();
+                line.clear();
+            }
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let e = session::starttls(stream).await.unwrap_err();
+        assert!(
+            e.to_string().contains("after its STARTTLS reply"),
+            "{}: {}",
+            injected,
+            e
+        );
+    });
+}
+
+#[test]
+fn test_poll_criteria() {
+    let flagged = [Folder {
+        name: "INBOX".into(),
+        criteria: Some("UNSEEN FLAGGED".into()),
+    }];
+    for use_seq in [false, true] {
+        block_on(async {
+            let opts = PollOptions {
+                use_seq,
+                ..Default::default()
+            };
+            let (status, sent) = poll_with(&flagged, &opts).await;
+            assert_eq!(2, status.new_count);
+            assert_eq!(5, status.count);
+            let search = match use_seq {
+                true => "SEARCH UNSEEN FLAGGED",
+                false => "UID SEARCH UNSEEN FLAGGED",
+            };
+            assert!(sent.iter().any(|c| c == search));
+        });
+    }
+}
+
+#[test]
+fn test_poll_keyword() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        let folders = [folder("INBOX"), folder("Lists")];
+        let opts = PollOptions {
+            keyword: Some("$Important".into()),
+            ..Default::default()
+        };
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        assert_eq!(Some(3), status.keyword);
+        assert_eq!(3, status.new_count);
+
+        let opts = PollOptions {
+            keyword: Some("$Rejected".into()),
+            ..Default::default()
+        };
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        assert_eq!(None, status.keyword);
+        assert_eq!(3, status.new_count);
+    });
+}
+
+#[test]
+fn test_poll_flagged() {
+    block_on(async {
+        let folders = [folder("INBOX"), folder("Lists")];
+        let opts = PollOptions {
+            flagged: Some("FLAGGED".into()),
+            ..Default::default()
+        };
+        let (status, sent) = poll_with(&folders, &opts).await;
+        assert_eq!(Some(3), status.flagged);
+        assert_eq!(
+            2,
+            sent.iter().filter(|c| *c == "UID SEARCH FLAGGED").count()
+        );
+    });
+}
+
+#[test]
+fn test_poll_cache_unseen() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let folders = [folder("INBOX")];
+        let opts = PollOptions {
+            cache_unseen: true,
+            ..Default::default()
+        };
+        let mut state = State::default();
+        for _ in 0..2 {
+            let status = session::poll(&mut s, &folders, None, &opts, &mut state)
+                .await
+                .unwrap();
+            assert_eq!(2, status.new_count);
+        }
+        let sent = log.lock().unwrap().clone();
+        assert_eq!(2, sent.iter().filter(|c| c.starts_with("EXAMINE")).count());
+        // Searched once, as the folder kept its size.
+        let searches: Vec<_> = sent
+            .iter()
+            .filter(|c| c.contains("SEARCH") || c.starts_with("STATUS"))
+            .collect();
+        assert_eq!(vec!["UID SEARCH UNSEEN"], searches);
+    });
+}
+
+#[test]
+fn test_poll_watch() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let opts = PollOptions {
+            watches: vec!["lists:Lists:ALL".parse::<Watch>().unwrap()],
+            ..Default::default()
+        };
+        let status = session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &opts,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(vec![("lists".to_string(), 1)], status.watched);
+        assert_eq!(2, status.new_count);
+        // INBOX is examined last, staying selected for IDLE.
+        let examined: Vec<_> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.starts_with("EXAMINE"))
+            .cloned()
+            .collect();
+        assert_eq!(vec!["EXAMINE \"Lists\"", "EXAMINE \"INBOX\""], examined);
+
+        let opts = PollOptions {
+            watches: vec!["gone:Gone:ALL".parse::<Watch>().unwrap()],
+            ..Default::default()
+        };
+        match session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &opts,
+            &mut State::default(),
+        )
+        .await
+        {
+            Err(PollError::Missing { folder, .. }) => assert_eq!("Gone", folder),
+            r => panic!("unexpected poll result {:?}", r.map(|s| s.new_count)),
+        }
+    });
+}
+
+#[test]
+fn test_poll_resync() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let folders = [
+            folder("INBOX"),
+            Folder {
+                name: "Lists".into(),
+                criteria: Some("UNSEEN".into()),
+            },
+        ];
+        let opts = PollOptions {
+            resync: true,
+            ..Default::default()
+        };
+        let mut state = State::default();
+        for _ in 0..2 {
+            let status = session::poll(&mut s, &folders, None, &opts, &mut state)
+                .await
+                .unwrap();
+            assert_eq!(3, status.new_count);
+            assert_eq!(8, status.count);
+        }
+        assert_eq!(Some(1), state.folders.get("Lists").map(|f| f.new_count));
+        // Unchanged, Lists is neither examined nor searched again.
+        let log = log.lock().unwrap().clone();
+        assert!(log
+            .iter()
+            .any(|c| c == "STATUS \"Lists\" (UIDVALIDITY HIGHESTMODSEQ)"));
+        assert_eq!(2, log.iter().filter(|c| *c == "EXAMINE \"INBOX\"").count());
+        assert_eq!(1, log.iter().filter(|c| *c == "EXAMINE \"Lists\"").count());
+        // INBOX is left selected by then, so searched rather than asked its
+        // STATUS.
+        assert_eq!(
+            1,
+            log.iter()
+                .filter(|c| *c == "STATUS \"INBOX\" (UNSEEN)")
+                .count()
+        );
+        assert_eq!(
+            vec!["UID SEARCH UNSEEN", "UID SEARCH UNSEEN"],
+            log.iter()
+                .filter(|c| c.starts_with("UID SEARCH"))
+                .collect::<Vec<_>>()
+        );
+    });
+}
+
+#[test]
+fn test_poll_resync_criteria() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let folders = [folder("INBOX"), folder("Lists")];
+        let mut opts = PollOptions {
+            resync: true,
+            ..Default::default()
+        };
+        let mut state = State::default();
+        session::poll(&mut s, &folders, None, &opts, &mut state)
+            .await
+            .unwrap();
+        // As after a restart with another --search.
+        opts.search = Some("FLAGGED".into());
+        session::poll(&mut s, &folders, None, &opts, &mut state)
+            .await
+            .unwrap();
+        let lists = state.folders.get("Lists").unwrap();
+        assert_eq!(Some("FLAGGED"), lists.criteria.as_deref());
+        // Not current, Lists is examined and searched again.
+        let log = log.lock().unwrap().clone();
+        assert!(!log.iter().any(|c| c.contains("HIGHESTMODSEQ")));
+        assert_eq!(2, log.iter().filter(|c| *c == "EXAMINE \"Lists\"").count());
+        assert_eq!(2, log.iter().filter(|c| *c == "UID SEARCH FLAGGED").count());
+    });
+}
+
+#[test]
+fn test_poll_color_rules() {
+    let rule = |criteria: &str, color: &str| ColorRule {
+        criteria: criteria.into(),
+        color: color.into(),
+    };
+    block_on(async {
+        let (mut s, _) = login().await;
+        let opts = PollOptions {
+            color_rules: vec![rule("DELETED", "#ff0000"), rule("UNSEEN", "#ffff00")],
+            ..Default::default()
+        };
+        let folders = [folder("INBOX"), folder("Lists")];
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        assert_eq!(Some("#ffff00"), status.color.as_deref());
+
+        let opts = PollOptions {
+            color_rules: vec![rule("DELETED", "#ff0000")],
+            ..Default::default()
+        };
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        assert_eq!(None, status.color);
+    });
+}
+
+#[test]
+fn test_poll_uids() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        let opts = PollOptions {
+            uids: true,
+            ..Default::default()
+        };
+        let folders = [folder("INBOX"), folder("Lists")];
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        let uids = status.uids.unwrap();
+        assert_eq!(Some(&vec![11, 14]), uids.get("INBOX"));
+        assert_eq!(Some(&vec![12]), uids.get("Lists"));
+
+        let status = session::poll(
+            &mut s,
+            &folders,
+            None,
+            &Default::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(None, status.uids);
+        assert_eq!(Some(&2), status.by_folder.get("INBOX"));
+        assert_eq!(Some(&1), status.by_folder.get("Lists"));
+    });
+}
+
+#[test]
+fn test_poll_quota() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let opts = PollOptions {
+            quota: true,
+            ..Default::default()
+        };
+        let folders = [folder("INBOX"), folder("Lists")];
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        assert_eq!(Some(85), status.quota);
+        let sent = log.lock().unwrap().clone();
+        assert_eq!("GETQUOTAROOT \"INBOX\"", sent.last().unwrap());
+
+        let status = session::poll(
+            &mut s,
+            &folders,
+            None,
+            &Default::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(None, status.quota);
+    });
+}
+
+#[test]
+fn test_archive_read() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let caps = Caps::from_names(&["MOVE"]);
+        let n = session::archive_read(&mut s, &caps, "INBOX", "Archive")
+            .await
+            .unwrap();
+        assert_eq!(2, n);
+        assert_eq!(
+            "UID MOVE 11,14 \"Archive\"",
+            log.lock().unwrap().last().unwrap()
+        );
+
+        log.lock().unwrap().clear();
+        let n = session::archive_read(&mut s, &Caps::default(), "INBOX", "Archive")
+            .await
+            .unwrap();
+        assert_eq!(2, n);
+        let sent = log.lock().unwrap().clone();
+        assert_eq!(
+            vec![
+                "SELECT \"INBOX\"",
+                "UID SEARCH SEEN",
+                "UID COPY 11,14 \"Archive\"",
+                "UID STORE 11,14 +FLAGS.SILENT (\\Deleted)",
+                "EXPUNGE",
+            ],
+            sent
+        );
+    });
+}
+
+#[test]
+fn test_poll_previews() {
+    block_on(async {
+        let opts = PollOptions {
+            previews: 1,
+            ..Default::default()
+        };
+        let (status, sent) = poll_with(&[folder("INBOX")], &opts).await;
+        assert_eq!(vec!["Alice — Café"], status.previews);
+        // Only the newest is fetched.
+        assert!(sent.contains(&"UID FETCH 14 ENVELOPE".to_string()));
+    });
+}
+
+#[test]
+fn test_poll_previews_expunged() {
+    for use_seq in [false, true] {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(serve_expunging(listener));
+            let c = async_imap::Client::new(TcpStream::connect(addr).await.unwrap());
+            let (mut s, _) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+                .await
+                .unwrap();
+            let opts = PollOptions {
+                previews: 1,
+                use_seq,
+                ..Default::default()
+            };
+            let status = session::poll(
+                &mut s,
+                &[folder("INBOX")],
+                None,
+                &opts,
+                &mut State::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(2, status.new_count);
+            // The first message went between SEARCH and FETCH, shifting
+            // sequence numbers past the last.
+            match use_seq {
+                false => assert_eq!(vec!["bob@example.com — New"], status.previews),
+                true => assert!(status.previews.is_empty(), "{:?}", status.previews),
+            }
+        });
+    }
+}
+
+/// Serve a mailbox of 4 messages, the first and last unseen, expunging the
+/// first once searched twice.
+async fn serve_expunging(listener: TcpListener) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut r = BufReader::new(stream.clone());
+    let mut w = stream;
+    w.write_all(b"* OK ready\r\n").await.unwrap();
+    let mut mailbox = vec![
+        (11, "Old", false),
+        (12, "", true),
+        (13, "", true),
+        (14, "New", false),
+    ];
+    let mut searches = 0;
+    let mut line = String::new();
+    while r.read_line(&mut line).await.unwrap_or(0) > 0 {
+        let (tag, rest) = line.trim_end().split_once(' ').unwrap();
+        let (by_uid, rest) = match rest.strip_prefix("UID ") {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (cmd, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+        let mut out = String::new();
+        if cmd == "FETCH" && searches == 2 {
+            mailbox.remove(0);
+            out.push_str("* 1 EXPUNGE\r\n");
+        }
+        let number = |seq: usize, uid: u32| if by_uid { uid } else { seq as u32 + 1 };
+        match cmd {
+            "CAPABILITY" => out.push_str("* CAPABILITY IMAP4rev1\r\n"),
+            "EXAMINE" => out.push_str(&format!("* {} EXISTS\r\n", mailbox.len())),
+            "SEARCH" => {
+                searches += 1;
+                let found: Vec<String> = (mailbox.iter().enumerate())
+                    .filter(|(_, m)| !m.2)
+                    .map(|(i, m)| number(i, m.0).to_string())
+                    .collect();
+                out.push_str(&format!("* SEARCH {}\r\n", found.join(" ")));
+            }
+            "FETCH" => {
+                let set = arg.split(' ').next().unwrap();
+                for n in set.split(',').map(|n| n.parse::<u32>().unwrap()) {
+                    let Some(i) = (0..mailbox.len()).find(|&i| number(i, mailbox[i].0) == n) else {
+                        continue;
+                    };
+                    out.push_str(&format!(
+                        "* {} FETCH (UID {} ENVELOPE (NIL \"{}\" ((NIL NIL \"bob\" \
+                         \"example.com\")) NIL NIL NIL NIL NIL NIL NIL))\r\n",
+                        i + 1,
+                        mailbox[i].0,
+                        mailbox[i].1
+                    ));
+                }
+            }
+            _ => (),
+        }
+        out.push_str(&format!("{} OK done\r\n", tag));
+        w.write_all(out.as_bytes()).await.unwrap();
+        line.clear();
+    }
+}
+
+#[test]
+fn test_poll_use_seq() {
+    block_on(async {
+        let opts = PollOptions {
+            use_seq: true,
+            keyword: Some("$Important".into()),
+            flagged: Some("FLAGGED".into()),
+            uids: true,
+            previews: 1,
+            ..Default::default()
+        };
+        let (status, sent) = poll_with(&[folder("INBOX"), folder("Lists")], &opts).await;
+        assert_eq!(vec!["Alice — Café"], status.previews);
+        assert!(sent.contains(&"FETCH 4 ENVELOPE".to_string()), "{:?}", sent);
+        assert!(!sent.iter().any(|c| c.starts_with("UID ")), "{:?}", sent);
+    });
+}
+
+#[test]
+fn test_poll_threads() {
+    block_on(async {
+        let c = async_imap::Client::new(ThreadIds::new(serve().await, true));
+        let (mut s, _) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+            .await
+            .unwrap();
+        let opts = PollOptions {
+            threads: true,
+            ..Default::default()
+        };
+        let folders = [folder("INBOX"), folder("Lists")];
+        let status = session::poll(&mut s, &folders, None, &opts, &mut State::default())
+            .await
+            .unwrap();
+        // Both INBOX messages are of one conversation.
+        assert_eq!(2, status.new_count);
+        assert_eq!(Some(&1), status.by_folder.get("INBOX"));
+    });
+}
+
+#[test]
+fn test_poll_gmail_query() {
+    block_on(async {
+        let opts = PollOptions {
+            gmail_query: Some("is:important \"to do\"".into()),
+            ..Default::default()
+        };
+        let (status, sent) = poll_with(&[folder("INBOX")], &opts).await;
+        assert_eq!(2, status.new_count);
+        assert!(
+            sent.contains(&r#"UID SEARCH UNSEEN X-GM-RAW "is:important \"to do\"""#.to_string()),
+            "{:?}",
+            sent
+        );
+    });
+}
+
+#[test]
+fn test_poll_select() {
+    block_on(async {
+        let opts = PollOptions {
+            select: SelectMode::Select,
+            ..Default::default()
+        };
+        let (status, sent) = poll_with(&[folder("INBOX")], &opts).await;
+        assert_eq!(5, status.count);
+        assert!(sent.contains(&"SELECT \"INBOX\"".to_string()), "{:?}", sent);
+        assert!(!sent.iter().any(|c| c.starts_with("EXAMINE")), "{:?}", sent);
+        // Counting never stores a flag.
+        assert!(!sent.iter().any(|c| c.contains("STORE")), "{:?}", sent);
+    });
+}
+
+#[test]
+fn test_poll_new_flag() {
+    block_on(async {
+        let opts = PollOptions {
+            new_flag: NewFlag::Recent,
+            ..Default::default()
+        };
+        let (status, sent) = poll_with(&[folder("INBOX")], &opts).await;
+        assert_eq!(2, status.new_count);
+        // Told by EXAMINE, without a SEARCH.
+        assert!(!sent.iter().any(|c| c.contains("SEARCH")), "{:?}", sent);
+        assert!(!sent.iter().any(|c| c.contains("(UNSEEN)")), "{:?}", sent);
+    });
+}
+
+#[test]
+fn test_poll_missing() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        match session::poll(
+            &mut s,
+            &[folder("INBOX"), folder("Gone")],
+            None,
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        {
+            Err(PollError::Missing { folder, .. }) => assert_eq!("Gone", folder),
+            r => panic!("unexpected poll result {:?}", r.map(|s| s.new_count)),
+        }
+    });
+}
+
+#[test]
+fn test_list_all() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        let folders = session::list_all(&mut s).await.unwrap();
+        assert_eq!(
+            vec![
+                ("Archive".to_string(), vec!["\\Noselect".to_string()]),
+                (
+                    "Entw&APw-rfe".to_string(),
+                    vec!["\\HasNoChildren".to_string(), "\\Drafts".to_string()]
+                ),
+            ],
+            folders
+        );
+        assert_eq!("Entwürfe", session::decode_utf7(&folders[1].0));
+    });
+}
+
+#[test]
+fn test_special_use() {
+    block_on(async {
+        let c = async_imap::Client::new(serve().await);
+        let (mut s, caps) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+            .await
+            .unwrap();
+        let drafts = session::special_use(&mut s, &caps.post, "\\drafts").await;
+        assert_eq!(vec!["Entw&APw-rfe".to_string()], drafts.unwrap());
+        let junk = session::special_use(&mut s, &caps.post, "\\Junk").await;
+        assert!(junk.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_start_cached_caps() {
+    block_on(async {
+        let c = async_imap::Client::new(serve().await);
+        let (s, caps) = session::start(c, AuthMethod::Auto, &mut creds("secret"), None)
+            .await
+            .unwrap();
+        drop(s);
+
+        let (stream, log) = serve_logged().await;
+        let c = async_imap::Client::new(stream);
+        let (mut s, cached) =
+            session::start(c, AuthMethod::Auto, &mut creds("secret"), Some(&caps))
+                .await
+                .unwrap();
+        assert_eq!(caps, cached);
+        s.logout().await.unwrap();
+        assert!(!log.lock().unwrap().iter().any(|c| c == "CAPABILITY"));
+    });
+}
+
+#[test]
+fn test_bad_login() {
+    block_on(async {
+        let c = async_imap::Client::new(serve().await);
+        match session::start(c, AuthMethod::Login, &mut creds("wrong"), None).await {
+            Err(StartError::Login(e)) => assert_eq!(
+                Some("AUTHENTICATIONFAILED"),
+                errors::response_code(&e).as_deref()
+            ),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("login should fail"),
+        }
+    });
+}
+
+#[test]
+fn test_connection_limit() {
+    block_on(async {
+        let c = async_imap::Client::new(serve().await);
+        match session::start(c, AuthMethod::Login, &mut creds("busy"), None).await {
+            Err(StartError::Limit(reason)) => assert!(reason.contains("[LIMIT]"), "{}", reason),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("login should fail"),
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        script_greeting(listener, "* BYE Too many connections from your IP\r\n");
+        let c = async_imap::Client::new(TcpStream::connect(addr).await.unwrap());
+        match session::start(c, AuthMethod::Login, &mut creds("secret"), None).await {
+            Err(StartError::Limit(reason)) => {
+                assert!(reason.contains("Too many connections"), "{}", reason)
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("login should fail"),
+        }
+    });
+}
+
+#[test]
+fn test_idle_wakes_on_flags() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut handle = s.idle();
+        handle.init().await.unwrap();
+        // Returns on the FETCH, well before the keep-alive.
+        let wake = async_std::future::timeout(
+            Duration::from_secs(5),
+            idle::wait(
+                &mut handle,
+                Duration::from_secs(60),
+                Duration::ZERO,
+                std::future::pending::<()>(),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(Wake::Changes, wake);
+        // Nothing more is pushed, the keep-alive passes.
+        let wake = idle::wait(
+            &mut handle,
+            Duration::from_secs(1),
+            Duration::ZERO,
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Wake::Quiet, wake);
+        handle.done().await.unwrap();
+    });
+}
+
+#[test]
+fn test_idle_debounce() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut handle = s.idle();
+        handle.init().await.unwrap();
+        let started = std::time::Instant::now();
+        let wake = idle::wait(
+            &mut handle,
+            Duration::from_secs(60),
+            Duration::from_millis(300),
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap();
+        // The FETCH is reported once more had time to come, well before the
+        // keep-alive.
+        assert_eq!(Wake::Changes, wake);
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(300), "{:?}", waited);
+        assert!(waited < Duration::from_secs(5), "{:?}", waited);
+        handle.done().await.unwrap();
+    });
+}
+
+#[test]
+fn test_noop_wait() {
+    block_on(async {
+        let (mut s, log) = login().await;
+        let opts = PollOptions::default();
+        session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &opts,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        let wake = idle::noop_wait(
+            &mut s,
+            Duration::from_millis(300),
+            Duration::from_millis(100),
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Wake::Quiet, wake);
+        assert!(log.lock().unwrap().iter().any(|c| c == "NOOP"));
+
+        session::poll(
+            &mut s,
+            &[folder("Lists")],
+            None,
+            &opts,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        let wake = async_std::future::timeout(
+            Duration::from_secs(5),
+            idle::noop_wait(
+                &mut s,
+                Duration::from_secs(60),
+                Duration::from_millis(10),
+                std::future::pending::<()>(),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(Wake::Changes, wake);
+        let wake = idle::noop_wait(
+            &mut s,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            async { "stop" },
+        )
+        .await
+        .unwrap();
+        assert_eq!(Wake::Interrupt("stop"), wake);
+    });
+}
+
+#[test]
+fn test_idle_interrupt() {
+    block_on(async {
+        let (mut s, _) = login().await;
+        session::poll(
+            &mut s,
+            &[folder("INBOX")],
+            None,
+            &PollOptions::default(),
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut handle = s.idle();
+        handle.init().await.unwrap();
+        // Drain the scripted FETCH, nothing else comes until DONE.
+        idle::wait(
+            &mut handle,
+            Duration::from_secs(60),
+            Duration::ZERO,
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap();
+        let interrupt = async_std::future::timeout(
+            Duration::from_secs(5),
+            idle::wait(
+                &mut handle,
+                Duration::from_secs(60),
+                Duration::ZERO,
+                async { "stop" },
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(Wake::Interrupt("stop"), interrupt);
+        // Left in sync for a clean logout.
+        let mut s = handle.done().await.unwrap();
+        s.logout().await.unwrap();
+    });
+}